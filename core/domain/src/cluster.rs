@@ -1,8 +1,10 @@
 use libp2p::{gossipsub::TopicHash, PeerId};
-use futures::{channel::{mpsc::{channel, Receiver, SendError, Sender}, oneshot}, AsyncReadExt, SinkExt, StreamExt};
-use networking::{event, libp2p::{Networking, NetworkingConfig}};
+use futures::{channel::{mpsc::{channel, Receiver, Sender}, oneshot}, AsyncReadExt, SinkExt, StreamExt};
+#[cfg(target_family="wasm")]
+use futures::FutureExt;
+use networking::{error::NetworkingError, event::{PubsubMessage, TopicValidator, Validation}, libp2p::{Networking, NetworkingConfig}};
 use crate::{message::{prefix_size_message, read_prefix_size_message}, protobuf::task::{self, Job, JobRequest, Status, SubmitJobResponse}};
-use std::{collections::HashMap, fmt::Error};
+use std::fmt::Error;
 use quick_protobuf::{deserialize_from_slice, serialize_into_vec};
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -27,7 +29,6 @@ struct InnerDomainCluster {
     command_rx: Receiver<Command>,
     manager: String,
     peer: Networking,
-    jobs: HashMap<TopicHash, Sender<TaskUpdateEvent>>,
 }
 
 enum Command {
@@ -46,30 +47,11 @@ enum Command {
 
 impl InnerDomainCluster {
     fn init(mut self) {
-        let event_receiver = self.peer.event_receiver.clone();
-        #[cfg(not(target_arch = "wasm32"))]
         spawn(async move {
-            loop {
-                let mut event_receiver = event_receiver.lock().await;
-                tokio::select! {
-                    Some(command) = self.command_rx.next() => self.handle_command(command).await,
-                    event = event_receiver.next() => self.handle_event(event).await,
-                    else => break,
-                }
+            while let Some(command) = self.command_rx.next().await {
+                self.handle_command(command).await;
             }
         });
-
-        #[cfg(target_arch = "wasm32")]
-        spawn(async move {
-            loop {
-                let mut event_receiver = event_receiver.lock().await;
-                futures::select! {
-                    command = self.command_rx.select_next_some() => self.handle_command(command).await,
-                    event = event_receiver.next() => self.handle_event(event).await,
-                    complete => break,
-                }
-            }
-        })
     }
 
     async fn handle_command(&mut self, command: Command) {
@@ -87,44 +69,6 @@ impl InnerDomainCluster {
         }
     }
 
-    async fn handle_event(&mut self, e: Option<event::Event>) {
-        match e {
-            Some(event::Event::PubSubMessageReceivedEvent { topic, message, from }) => {
-                let mut task = deserialize_from_slice::<task::Task>(&message).expect("can't deserialize task");
-                if let Some(tx) = self.jobs.get_mut(&topic) {
-                    if let Err(e) = tx.send(TaskUpdateEvent {
-                        topic: topic.clone(),
-                        from: from,
-                        result: TaskUpdateResult::Ok(task.clone()),
-                    }).await {
-                        if SendError::is_disconnected(&e) {
-                            self.jobs.remove(&topic);
-                            return;
-                        }
-                        task.status = Status::FAILED;
-                        if let Err(e) = tx.send(TaskUpdateEvent {
-                            topic: topic.clone(),
-                            from: from,
-                            result: TaskUpdateResult::Ok(task.clone()),
-                        }).await {
-                            eprintln!("Error sending failed task update: {:?}", e);
-                            if SendError::is_disconnected(&e) {
-                                self.jobs.remove(&topic);
-                                return;
-                            }
-                        }
-                        // // TODO: send failed task update with error
-                        // self.peer.publish(topic.to_string().clone(), serialize_into_vec(&task).expect("can't serialize task update")).await.unwrap();
-                    }
-                }
-            }
-            Some(event::Event::NewNodeRegistered { node }) => {
-                tracing::debug!("New node registered: {:?}", node.name);
-            }
-            _ => {}
-        }
-    }
-
     async fn submit_job(&mut self, job: &JobRequest, mut tx: Sender<TaskUpdateEvent>) {
         let res = self.peer.client.send(prefix_size_message(job), self.manager.clone(), "/jobs/v1".to_string(), 0).await;
         if let Err(e) = res {
@@ -139,9 +83,48 @@ impl InnerDomainCluster {
         self.subscribe_to_job(job.job_id, tx).await
     }
 
-    async fn subscribe_to_job(&mut self, job_id: String, tx: Sender<TaskUpdateEvent>) {
-        self.peer.client.subscribe(job_id.clone()).await.unwrap();
-        self.jobs.insert(TopicHash::from_raw(job_id.clone()), tx);
+    async fn subscribe_to_job(&mut self, job_id: String, mut tx: Sender<TaskUpdateEvent>) {
+        let topic_job_id = job_id.clone();
+        let validator = TopicValidator::new(move |message: PubsubMessage| {
+            let valid = matches!(deserialize_from_slice::<task::Task>(&message.message), Ok(task) if task.job_id == topic_job_id);
+            async move {
+                if valid { Validation::Accept } else { Validation::Reject }
+            }
+        });
+        if let Err(e) = self.peer.client.set_topic_validator(job_id.clone(), validator).await {
+            tracing::error!("Error setting validator for job {}: {:?}", job_id, e);
+            tx.close_channel();
+            return;
+        }
+
+        let mut updates = match self.peer.client.subscribe(job_id.clone()).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                tracing::error!("Error subscribing to job {}: {:?}", job_id, e);
+                tx.close_channel();
+                return;
+            }
+        };
+
+        // The subscription lives as long as somebody listens to the task updates.
+        spawn(async move {
+            while let Some(PubsubMessage { topic, message, from }) = updates.next().await {
+                let task = match deserialize_from_slice::<task::Task>(&message) {
+                    Ok(task) => task,
+                    Err(e) => {
+                        tracing::error!("Can't deserialize task update on {}: {:?}", topic, e);
+                        continue;
+                    }
+                };
+                if tx.send(TaskUpdateEvent {
+                    topic,
+                    from,
+                    result: TaskUpdateResult::Ok(task),
+                }).await.is_err() {
+                    break;
+                }
+            }
+        });
     }
 
     async fn monitor_jobs(&mut self) -> Receiver<Job> {
@@ -179,13 +162,27 @@ pub struct DomainCluster {
 }
 
 impl DomainCluster {
-    pub fn new(manager_addr: String, node_name: String, join_as_relay: bool, port: u16, enable_websocket: bool, enable_webrtc: bool, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Self {
+    /// Blocking version of [`DomainCluster::start`], see [`Networking::new`].
+    #[cfg(not(target_family="wasm"))]
+    pub fn new(manager_addr: String, node_name: String, join_as_relay: bool, port: u16, enable_websocket: bool, enable_webrtc: bool, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Result<Self, NetworkingError> {
+        runtime::block_on(Self::start(manager_addr, node_name, join_as_relay, port, enable_websocket, enable_webrtc, private_key, private_key_path))
+    }
+
+    /// Synchronous version of [`DomainCluster::start`], see [`Networking::new`].
+    #[cfg(target_family="wasm")]
+    pub fn new(manager_addr: String, node_name: String, join_as_relay: bool, port: u16, enable_websocket: bool, enable_webrtc: bool, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Result<Self, NetworkingError> {
+        Self::start(manager_addr, node_name, join_as_relay, port, enable_websocket, enable_webrtc, private_key, private_key_path).now_or_never().unwrap_or_else(|| {
+            Err(NetworkingError::InvalidConfig("listeners can't be awaited synchronously, use DomainCluster::start".to_string()))
+        })
+    }
+
+    /// Starts networking and joins the domain cluster managed by `manager_addr`. Resolves once
+    /// the listeners are bound.
+    pub async fn start(manager_addr: String, node_name: String, join_as_relay: bool, port: u16, enable_websocket: bool, enable_webrtc: bool, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Result<Self, NetworkingError> {
         #[cfg(not(target_family="wasm"))]
-        tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).init();
+        let _ = tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).try_init();
 
-        let networking = Networking::new(&NetworkingConfig {
-            bootstrap_nodes: vec![manager_addr.clone()],
-            relay_nodes: vec![manager_addr.clone()],
+        Self::start_with_config(manager_addr, NetworkingConfig {
             private_key,
             private_key_path,
             enable_mdns: false,
@@ -195,23 +192,32 @@ impl DomainCluster {
             port,
             enable_websocket,
             enable_webrtc,
-        }).unwrap();
+            ..Default::default()
+        }).await
+    }
+
+    /// Like [`DomainCluster::start`] with full control over networking, e.g. to run a whole cluster
+    /// in one process over the memory transport. The domain manager is added to the bootstrap and
+    /// relay nodes.
+    pub async fn start_with_config(manager_addr: String, mut cfg: NetworkingConfig) -> Result<Self, NetworkingError> {
+        cfg.bootstrap_nodes.push(manager_addr.clone());
+        cfg.relay_nodes.push(manager_addr.clone());
+        let networking = Networking::start(&cfg).await?;
         let domain_manager_id = manager_addr.split("/").last().unwrap().to_string();
 
         let (tx, rx) = channel::<Command>(3072);
         let dc = InnerDomainCluster {
             manager: domain_manager_id.clone(),
             peer: networking.clone(),
-            jobs: HashMap::new(),
             command_rx: rx,
         };
         dc.init();
 
-        DomainCluster {
+        Ok(DomainCluster {
             sender: tx,
             peer: networking.clone(),
             manager_id: domain_manager_id.clone(),
-        }
+        })
     }
 
     pub async fn submit_job(&mut self, job: &JobRequest) -> Receiver<TaskUpdateEvent> {
@@ -159,8 +159,10 @@ pub struct DomainCluster {
 #[wasm_bindgen]
 impl DomainCluster {
     #[wasm_bindgen(constructor)]
-    pub fn new(domain_manager_addr: String, name: String, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Self {
-        Self { inner: Arc::new(Mutex::new(r_DomainCluster::new(domain_manager_addr, name, false, 0, false, false, private_key, private_key_path))) }   
+    pub fn new(domain_manager_addr: String, name: String, private_key: Option<Vec<u8>>, private_key_path: Option<String>) -> Result<Self, JsValue> {
+        let cluster = r_DomainCluster::new(domain_manager_addr, name, false, 0, false, false, private_key, private_key_path)
+            .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+        Ok(Self { inner: Arc::new(Mutex::new(cluster)) })
     }
 
     #[wasm_bindgen]
@@ -0,0 +1,67 @@
+#![cfg(not(target_family="wasm"))]
+
+use std::{collections::HashSet, time::Duration};
+
+use domain::cluster::DomainCluster;
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use networking::{event::{Event, EventStream}, libp2p::{Networking, NetworkingConfig, TransportMode}};
+
+fn memory_config(name: &str) -> NetworkingConfig {
+    NetworkingConfig {
+        port: 0,
+        enable_kdht: true,
+        enable_mdns: false,
+        // a new key for every node
+        private_key_path: None,
+        name: name.to_string(),
+        transport: TransportMode::Memory,
+        ..Default::default()
+    }
+}
+
+async fn wait_for_peers(events: &mut EventStream, mut peers: HashSet<String>) {
+    while !peers.is_empty() {
+        match events.next().await {
+            Some(Ok(Event::PeerConnected { peer_id, .. })) => {
+                peers.remove(&peer_id.to_string());
+            }
+            Some(_) => {}
+            None => panic!("event stream closed"),
+        }
+    }
+}
+
+// A domain manager, a data node and a client run in this process without opening any socket.
+#[tokio::test]
+async fn cluster_over_memory_transport() {
+    let mut manager = Networking::start(&NetworkingConfig {
+        enable_relay_server: true,
+        ..memory_config("memory-cluster/manager")
+    }).await.unwrap();
+    let mut manager_events = manager.events();
+    let listen_addr = manager.client.listen_addresses().await.unwrap().remove(0);
+    let manager_addr = format!("{}/p2p/{}", listen_addr, manager.id);
+
+    let mut node = DomainCluster::start_with_config(manager_addr.clone(), memory_config("memory-cluster/data-node")).await.unwrap();
+    let mut client = DomainCluster::start_with_config(manager_addr, memory_config("memory-cluster/client")).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), wait_for_peers(&mut manager_events, HashSet::from([node.peer.id.clone(), client.peer.id.clone()])))
+        .await
+        .expect("data node and client didn't connect to the manager");
+
+    let mut echo_handler = node.peer.client.set_stream_handler("/echo/v1".to_string()).await.unwrap();
+    tokio::spawn(async move {
+        while let Some((_, mut stream)) = echo_handler.next().await {
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.close().await.unwrap();
+        }
+    });
+
+    // the client finds the data node through the manager
+    let mut s = client.peer.client.send(b"ping".to_vec(), node.peer.id.clone(), "/echo/v1".to_string(), 10000).await.unwrap();
+    s.close().await.unwrap();
+    let mut buf = Vec::new();
+    s.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"ping");
+}
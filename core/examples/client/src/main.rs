@@ -23,7 +23,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let base_path = format!("./volume/{}", name);
     let private_key_path = format!("{}/pkey", base_path);
 
-    let domain_cluster = DomainCluster::new(domain_manager.clone(), name, false, port, false, false, None, Some(private_key_path));
+    let domain_cluster = DomainCluster::start(domain_manager.clone(), name, false, port, false, false, None, Some(private_key_path)).await?;
     let _peer_id = domain_cluster.peer.id.clone();
     let mut remote_datastore = RemoteDatastore::new(domain_cluster.clone());
     
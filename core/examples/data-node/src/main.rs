@@ -1,7 +1,7 @@
 use domain::{cluster::DomainCluster, datastore::remote::{CONSUME_DATA_PROTOCOL_V1, PRODUCE_DATA_PROTOCOL_V1}, message::read_prefix_size_message, protobuf::{domain_data::Metadata, task::{ConsumeDataInputV1, DomainClusterHandshake, Status, Task}}};
 use jsonwebtoken::{decode, DecodingKey,Validation, Algorithm};
 use libp2p::Stream;
-use networking::libp2p::Networking;
+use networking::libp2p::{Networking, NodeResources};
 use quick_protobuf::{deserialize_from_slice, serialize_into_vec};
 use tokio::{self, select};
 use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
@@ -27,10 +27,8 @@ async fn handshake(stream: &mut Stream) -> Result<TaskTokenClaim, Box<dyn std::e
     decode_jwt(header.access_token.as_str())
 }
 
-async fn store_data_v1(base_path: String, mut stream: Stream, mut c: Networking) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let claim = handshake(&mut stream).await?;
-    let job_id = claim.job_id.clone();
-    c.client.subscribe(job_id.clone()).await?;
+async fn store_data_v1(base_path: String, mut stream: Stream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    handshake(&mut stream).await?;
     let mut data_ids = Vec::<String>::new();
 
     loop {
@@ -94,7 +92,7 @@ async fn store_data_v1(base_path: String, mut stream: Stream, mut c: Networking)
 
 async fn serve_data_v1(base_path: String, mut stream: Stream, mut c: Networking) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let header = handshake(&mut stream).await?;
-    c.client.subscribe(header.job_id.clone()).await?;
+    // job updates aren't read, publishing the result below doesn't need a subscription
     let mut buf = Vec::new();
     let _ = stream.read_to_end(&mut buf).await?;
     let input = deserialize_from_slice::<ConsumeDataInputV1>(&buf)?;
@@ -187,10 +185,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let private_key_path = format!("{}/pkey", base_path);
 
     let _domain_manager_id = domain_manager.split("/").last().unwrap().to_string();
-    let domain_cluster = DomainCluster::new(domain_manager.clone(), name, false, port, true, true, None, Some(private_key_path));
+    let domain_cluster = DomainCluster::start(domain_manager.clone(), name, false, port, true, true, None, Some(private_key_path)).await?;
     let mut n = domain_cluster.peer;
     let mut produce_handler = n.client.set_stream_handler(PRODUCE_DATA_PROTOCOL_V1.to_string()).await.unwrap();
     let mut consume_handler = n.client.set_stream_handler(CONSUME_DATA_PROTOCOL_V1.to_string()).await.unwrap();
+    let cpu_cores = std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+    n.client.set_resources(NodeResources { cpu_cores, ..Default::default() }).await.unwrap();
     let _ = std::fs::remove_dir_all(format!("{}/output/domain_data", base_path));
     std::fs::create_dir_all(format!("{}/output/domain_data", base_path)).expect("Failed to create domain_data directory");
 
@@ -199,9 +199,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             Some((_, stream)) = produce_handler.next() => {
                 // let tx = tx.clone();
                 let base_path = base_path.clone();
-                let n = n.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = store_data_v1(base_path, stream, n).await {
+                    if let Err(e) = store_data_v1(base_path, stream).await {
                         println!("Error storing data: {}", e);
                     }
                 });
@@ -1,6 +1,6 @@
 use jsonwebtoken::{encode, EncodingKey, Header};
-use libp2p::Stream;
-use networking::{client::Client, event, libp2p::{Networking, NetworkingConfig}};
+use libp2p::{Multiaddr, Stream};
+use networking::{client::Subscription, event::{self, PubsubMessage, TopicValidator, Validation}, libp2p::{Networking, NetworkingConfig}};
 use nodes_management::NodesManagement;
 use quick_protobuf::{deserialize_from_slice, serialize_into_vec};
 use tasks_management::{task_id, TaskHandler, TasksManagement};
@@ -62,31 +62,30 @@ struct DomainManager {
 impl DomainManager {
     fn new(domain_id: String, peer: Networking) -> Self {
         DomainManager {
+            node_mgmt: NodesManagement::new(peer.clone()),
             peer,
             domain_id,
             task_mgmt: TasksManagement::new(),
-            node_mgmt: NodesManagement::new(),
         }
     }
 
     #[tracing::instrument]
     async fn start(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let event_receiver = self.peer.event_receiver.clone();
+        let mut events = self.peer.events();
         let mut job_handler = self.peer.client.set_stream_handler("/jobs/v1".to_string()).await.unwrap();
         let mut monitor_handler = self.peer.client.set_stream_handler("/monitor/v1".to_string()).await.unwrap();
 
         loop {
-            let mut rx_guard = event_receiver.lock().await;
             select! {
                 Some((_, stream)) = job_handler.next() => {
                     let task_mgmt = self.task_mgmt.clone();
                     let node_mgmt = self.node_mgmt.clone();
                     let peer = self.peer.clone();
-                    spawn(DomainManager::accept_job(node_mgmt, task_mgmt, peer.client.clone(), stream));
+                    spawn(DomainManager::accept_job(node_mgmt, task_mgmt, peer, stream));
                 }
-                e = rx_guard.next() => {
+                e = events.next() => {
                     match e {
-                        Some(e) => {
+                        Some(Ok(e)) => {
                             match e {
                                 event::Event::NewNodeRegistered { node } => {
                                     let mut node_mgmt = self.node_mgmt.clone();
@@ -96,18 +95,17 @@ impl DomainManager {
                                         });
                                     }
                                 }
-                                event::Event::PubSubMessageReceivedEvent { from, message, .. } => {
-                                    if from.is_none() || from.unwrap().to_string() != self.peer.id.clone() {
-                                        let task_event = deserialize_from_slice::<task::Task>(&message)?;
-                                        let task_mgmt = self.task_mgmt.clone();
-                                        let node_mgmt = self.node_mgmt.clone();
-                                        spawn(async move {
-                                            task_mgmt.update_task(&task_event, node_mgmt).await;
-                                        });
-                                    }
+                                event::Event::PeerDisconnected { peer_id, num_established: 0, cause } => {
+                                    tracing::info!("Node {} disconnected: {:?}", peer_id, cause);
+                                    let mut node_mgmt = self.node_mgmt.clone();
+                                    spawn(async move {
+                                        node_mgmt.unregister_node(&peer_id.to_string()).await;
+                                    });
                                 }
+                                _ => {}
                             }
                         }
+                        Some(Err(lagged)) => tracing::warn!("{}", lagged),
                         None => break
                     }
                 }
@@ -138,7 +136,7 @@ impl DomainManager {
     }
 
     #[tracing::instrument]
-    async fn accept_job(node_mgmt: NodesManagement, task_mgmt: TasksManagement, mut peer: Client, stream: Stream) {
+    async fn accept_job(node_mgmt: NodesManagement, task_mgmt: TasksManagement, mut peer: Networking, stream: Stream) {
         let (reader, mut writer) = stream.split();
         let job = read_prefix_size_message::<JobRequest>(reader).await.expect("failed to load job request");
 
@@ -147,7 +145,9 @@ impl DomainManager {
         let result = hasher.finalize();
         let job_id = hex::encode(result);
         println!("Job received: {:?}-{}", job.name, job_id);
-        peer.subscribe(job_id.clone()).await.expect("failed to subscribe to job");
+        peer.client.set_topic_validator(job_id.clone(), DomainManager::task_update_validator(task_mgmt.clone(), job_id.clone())).await.expect("failed to set job validator");
+        let updates = peer.client.subscribe(job_id.clone()).await.expect("failed to subscribe to job");
+        spawn(DomainManager::watch_job(node_mgmt.clone(), task_mgmt.clone(), peer.id.clone(), updates));
 
         let mut resp = task::SubmitJobResponse {
             job_id: job_id.clone(),
@@ -180,6 +180,47 @@ impl DomainManager {
         task_mgmt.push_tasks(tasks).await;
     }
 
+    // Only the nodes a task is assigned to may report on it.
+    fn task_update_validator(task_mgmt: TasksManagement, job_id: String) -> TopicValidator {
+        TopicValidator::new(move |message: PubsubMessage| {
+            let task_mgmt = task_mgmt.clone();
+            let job_id = job_id.clone();
+            async move {
+                let task = match deserialize_from_slice::<task::Task>(&message.message) {
+                    Ok(task) => task,
+                    Err(_) => return Validation::Reject,
+                };
+                let from = match message.from {
+                    Some(from) => from.to_string(),
+                    None => return Validation::Reject,
+                };
+                if task.job_id != job_id {
+                    return Validation::Reject;
+                }
+                match task_mgmt.get_task(&task_id(&job_id, &task.name)).await {
+                    Some(th) if th.task.sender == from || th.task.receiver.as_deref() == Some(from.as_str()) => Validation::Accept,
+                    Some(_) => {
+                        tracing::warn!("Rejected update of task {} from unauthorised node {}", task.name, from);
+                        Validation::Reject
+                    }
+                    None => Validation::Ignore,
+                }
+            }
+        })
+    }
+
+    async fn watch_job(node_mgmt: NodesManagement, task_mgmt: TasksManagement, local_id: String, mut updates: Subscription) {
+        while let Some(PubsubMessage { from, message, .. }) = updates.next().await {
+            if from.is_some_and(|from| from.to_string() == local_id) {
+                continue;
+            }
+            match deserialize_from_slice::<task::Task>(&message) {
+                Ok(task_event) => task_mgmt.update_task(&task_event, node_mgmt.clone()).await,
+                Err(e) => tracing::error!("Failed to deserialize task update on {}: {:?}", updates.topic(), e),
+            }
+        }
+    }
+
     #[tracing::instrument]
     async fn run_task(domain_id:&str, mut peer: Networking, th: &TaskHandler, task_mgmt: TasksManagement, node_mgmt: NodesManagement) {
         let mut serialized_input: Vec<u8> = vec![];
@@ -288,6 +329,16 @@ impl DomainManager {
     * Usage: cargo run --package domain-manager <port> <name> [private_key_path]
     * Example: cargo run --package domain-manager 18804 domain_manager 
  */
+fn parse_addresses(addresses: &str) -> Vec<Multiaddr> {
+    addresses.split(',').filter(|address| !address.trim().is_empty()).filter_map(|address| match address.trim().parse() {
+        Ok(address) => Some(address),
+        Err(e) => {
+            eprintln!("Ignoring invalid address {address}: {e}");
+            None
+        }
+    }).collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut args: Vec<String> = std::env::args().collect();
@@ -321,8 +372,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         name,
         enable_websocket: true,
         enable_webrtc: true,
+        // e.g. METRICS_ADDRESS=127.0.0.1:9090 to let Prometheus scrape the manager
+        metrics_address: std::env::var("METRICS_ADDRESS").ok().and_then(|address| address.parse().ok()),
+        // e.g. EXTERNAL_ADDRS=/ip4/203.0.113.7/tcp/18800,/ip4/203.0.113.7/udp/18800/quic-v1 behind port forwarding
+        external_addrs: std::env::var("EXTERNAL_ADDRS").map(|addresses| parse_addresses(&addresses)).unwrap_or_default(),
+        ..Default::default()
     };
-    let c = Networking::new(cfg)?;
+    let c = Networking::start(cfg).await?;
     let mut domain_manager = DomainManager::new(domain_id, c);
     
     domain_manager.start().await
@@ -1,13 +1,15 @@
 use std::{collections::{HashMap, VecDeque}, fmt::Debug, sync::Arc};
 use domain::protobuf::task::CapabilityFilters;
 use async_trait::async_trait;
-use networking::libp2p::Node;
+use networking::libp2p::{Networking, Node};
 use tokio::sync::{Mutex, oneshot};
 
 #[async_trait]
 trait LoadBalancer: Send + Sync + Debug {
-    async fn find_key(&mut self, nodes: HashMap<String, Node>, key: &str) -> Option<Node>;
+    async fn find_key(&mut self, nodes: HashMap<String, Node>, key: &str, filter: &(dyn Fn(&Node) -> bool + Send + Sync)) -> Option<Node>;
     async fn add_key(&mut self, key: &str, value: &str);
+    async fn remove_key(&mut self, key: &str, value: &str);
+    async fn remove_value(&mut self, value: &str);
 }
 
 #[derive(Debug)]
@@ -27,31 +29,34 @@ impl RoundRobin {
 
 #[async_trait]
 impl LoadBalancer for RoundRobin {
-    #[tracing::instrument]
-    async fn find_key(&mut self, nodes: HashMap<String, Node>, endpoint: &str) -> Option<Node> {
+    #[tracing::instrument(skip(filter))]
+    async fn find_key(&mut self, nodes: HashMap<String, Node>, endpoint: &str, filter: &(dyn Fn(&Node) -> bool + Send + Sync)) -> Option<Node> {
         let node_ids = self.capabilities.get(endpoint);
 
         match node_ids {
-            Some(node_ids) => {
+            Some(node_ids) if !node_ids.is_empty() => {
                 let index = self.node_indices.get(endpoint).unwrap();
-                let node_id = node_ids.get(index.load(std::sync::atomic::Ordering::Relaxed) % node_ids.len()).unwrap();
-                let node = nodes.get(node_id);
-                match node {
-                    Some(node) => {
-                        index.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let start = index.load(std::sync::atomic::Ordering::Relaxed);
+                // next node in turn that passes the filter
+                for offset in 0..node_ids.len() {
+                    let node_id = &node_ids[(start + offset) % node_ids.len()];
+                    if let Some(node) = nodes.get(node_id).filter(|node| filter(node)) {
+                        index.store(start + offset + 1, std::sync::atomic::Ordering::Relaxed);
                         return Some(node.clone());
                     }
-                    None => None
                 }
+                None
             }
-            None => None
+            _ => None
         }
     }
     #[tracing::instrument]
     async fn add_key(&mut self, endpoint: &str, node_id: &str) {
         match self.capabilities.get_mut(endpoint) {
             Some(node_ids) => {
-                node_ids.push(node_id.to_string());
+                if !node_ids.iter().any(|id| id == node_id) {
+                    node_ids.push(node_id.to_string());
+                }
             }
             None => {
                 self.capabilities.insert(endpoint.to_string(), vec![node_id.to_string()]);
@@ -59,6 +64,30 @@ impl LoadBalancer for RoundRobin {
             }
         }
     }
+    #[tracing::instrument]
+    async fn remove_key(&mut self, endpoint: &str, node_id: &str) {
+        if let Some(node_ids) = self.capabilities.get_mut(endpoint) {
+            node_ids.retain(|id| id != node_id);
+        }
+    }
+    #[tracing::instrument]
+    async fn remove_value(&mut self, node_id: &str) {
+        for node_ids in self.capabilities.values_mut() {
+            node_ids.retain(|id| id != node_id);
+        }
+    }
+}
+
+// Nodes that didn't announce their resources only pass filters without minimums.
+fn satisfies(node: &Node, capability_filter: &CapabilityFilters) -> bool {
+    if capability_filter.min_cpu.is_none() && capability_filter.min_gpu.is_none() {
+        return true;
+    }
+    let Some(resources) = node.resources.as_ref() else {
+        return false;
+    };
+    capability_filter.min_cpu.map_or(true, |min| i64::from(resources.cpu_cores) >= i64::from(min))
+        && capability_filter.min_gpu.map_or(true, |min| i64::from(resources.gpu_count) >= i64::from(min))
 }
 
 #[derive(Clone, Debug)]
@@ -66,27 +95,36 @@ pub struct NodesManagement {
     nodes: Arc<Mutex<HashMap<String, Node>>>,
     load_balancer: Arc<Mutex<dyn LoadBalancer>>,
     requests: Arc<Mutex<HashMap<String, VecDeque<oneshot::Sender<String>>>>>,
+    peer: Networking,
 }
 
 impl NodesManagement {
-    pub fn new() -> Self {
+    pub fn new(peer: Networking) -> Self {
         NodesManagement {
             nodes: Arc::new(Mutex::new(HashMap::new())),
             load_balancer: Arc::new(Mutex::new(RoundRobin::new())),
             requests: Arc::new(Mutex::new(HashMap::new())),
+            peer,
         }
     }
 
+    // Called for identify infos and resource announcements alike, which each describe part of the node.
     #[tracing::instrument]
-    pub async fn register_node(&mut self, node: Node) {
+    pub async fn register_node(&mut self, mut node: Node) {
         let node_id = node.id.clone();
-        let mut exists = false;
 
         let mut nodes = self.nodes.lock().await;
-        if nodes.insert(node_id.clone(), node.clone()).is_some() {
-            exists = true;
-            tracing::warn!("Node {} already registered", node_id);
-        }
+        let known_capabilities = match nodes.get(&node_id) {
+            Some(existing) => {
+                tracing::debug!("Node {} updated", node_id);
+                if node.resources.is_none() {
+                    node.resources = existing.resources.clone();
+                }
+                existing.capabilities.clone()
+            }
+            None => vec![],
+        };
+        nodes.insert(node_id.clone(), node.clone());
         drop(nodes);
 
         for capability in node.capabilities.iter() {
@@ -102,18 +140,62 @@ impl NodesManagement {
                 }
             }
             drop(requests);
-            if !exists {
-                let mut load_balancer = self.load_balancer.lock().await;
-                load_balancer.add_key(capability, &node_id.clone()).await;
-            }
+            let mut load_balancer = self.load_balancer.lock().await;
+            load_balancer.add_key(capability, &node_id).await;
+        }
+
+        // a node may stop serving a capability, e.g. when its stream handler is removed
+        let mut load_balancer = self.load_balancer.lock().await;
+        for capability in known_capabilities.iter().filter(|capability| !node.capabilities.contains(capability)) {
+            load_balancer.remove_key(capability, &node_id).await;
         }
     }
 
+    #[tracing::instrument]
+    pub async fn unregister_node(&mut self, node_id: &str) {
+        let mut nodes = self.nodes.lock().await;
+        if nodes.remove(node_id).is_none() {
+            return;
+        }
+        drop(nodes);
+
+        let mut load_balancer = self.load_balancer.lock().await;
+        load_balancer.remove_value(node_id).await;
+        tracing::info!("Node {} unregistered", node_id);
+    }
+
     #[tracing::instrument]
     pub async fn find_node(&mut self, capability_filter: CapabilityFilters) -> Option<Node> {
+        if let Some(node) = self.find_registered_node(&capability_filter).await {
+            return Some(node);
+        }
+
+        // Nodes that never connected to the manager can still be found through the DHT.
+        let providers = match self.peer.client.find_providers(capability_filter.endpoint.clone()).await {
+            Ok(providers) => providers,
+            Err(e) => {
+                tracing::debug!("No provider found for {}: {}", capability_filter.endpoint, e);
+                return None;
+            }
+        };
+        for provider in providers {
+            if provider == self.peer.id || self.nodes.lock().await.contains_key(&provider) {
+                continue;
+            }
+            self.register_node(Node {
+                id: provider.clone(),
+                name: provider,
+                capabilities: vec![capability_filter.endpoint.clone()],
+                resources: None,
+            }).await;
+        }
+        self.find_registered_node(&capability_filter).await
+    }
+
+    async fn find_registered_node(&self, capability_filter: &CapabilityFilters) -> Option<Node> {
         let nodes = self.nodes.lock().await;
         let mut load_balancer = self.load_balancer.lock().await;
-        load_balancer.find_key(nodes.clone(), &capability_filter.endpoint).await
+        load_balancer.find_key(nodes.clone(), &capability_filter.endpoint, &|node| satisfies(node, capability_filter)).await
     }
 
     #[tracing::instrument]
@@ -21,16 +21,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         name: "relay-example/relay".to_string(),
         enable_websocket: true,
         enable_webrtc: true,
+        ..Default::default()
     };
-    let mut relay = Networking::new(relay_cfg)?;
+    let mut relay = Networking::start(relay_cfg).await?;
     let protocol = "/chat".to_string();
     let mut chat_handler = relay.client.set_stream_handler(protocol).await.unwrap();
 
     let _bootstrap_addr = format!("/ip4/192.168.31.39/udp/8080/quic-v1/p2p/{}", relay.id.clone());
 
+    let mut relay_events = relay.events();
     loop {
-        let relay_events = relay.event_receiver.clone();
-        let mut relay_events = relay_events.lock().await;
         select! {
             Some((_, stream)) = chat_handler.next() => {
                 let mut stream = stream;
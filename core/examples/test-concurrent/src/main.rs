@@ -1,14 +1,13 @@
-use std::time::Duration;
+use std::collections::HashSet;
 
 use futures::{AsyncReadExt, StreamExt, AsyncWriteExt};
-use networking::libp2p::{Networking, NetworkingConfig};
-use tokio::time::sleep;
+use networking::{event::Event, libp2p::{Networking, NetworkingConfig, TransportMode}};
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).init();
     let networking = NetworkingConfig {
-        port: 8080,
+        port: 0,
         bootstrap_nodes: vec![],
         enable_relay_server: false,
         enable_kdht: true,
@@ -19,6 +18,8 @@ async fn main() {
         name: "test-concurrent/bootstrap".to_string(),
         enable_websocket: false,
         enable_webrtc: false,
+        transport: TransportMode::Memory,
+        ..Default::default()
     };
 
     let protocol = "/chat/v1".to_string();
@@ -26,7 +27,9 @@ async fn main() {
     let protocol_clone_clone = protocol.clone();
     let protocol_clone_clone_clone = protocol.clone();
 
-    let mut bootstrap = Networking::new(&networking).unwrap();
+    let mut bootstrap = Networking::start(&networking).await.unwrap();
+    let mut bootstrap_events = bootstrap.events();
+    let bootstrap_addr = bootstrap.client.listen_addresses().await.unwrap().remove(0);
     let mut chat_protocol = bootstrap.client.set_stream_handler(protocol.clone()).await.unwrap();
 
     let bootstrap_id = bootstrap.id.clone();
@@ -49,8 +52,8 @@ async fn main() {
     });
 
     let peer_a_cfg = NetworkingConfig {
-        port: 8082,
-        bootstrap_nodes: vec![format!("/ip4/127.0.0.1/udp/8080/quic-v1/p2p/{}", bootstrap_id.clone())],
+        port: 0,
+        bootstrap_nodes: vec![format!("{}/p2p/{}", bootstrap_addr, bootstrap_id)],
         enable_relay_server: false,
         enable_kdht: true,
         enable_mdns: false,
@@ -60,13 +63,15 @@ async fn main() {
         name: "test-concurrent/peer-a".to_string(),
         enable_websocket: false,
         enable_webrtc: false,
+        transport: TransportMode::Memory,
+        ..Default::default()
     };
-    let mut peer_a = Networking::new(&peer_a_cfg).unwrap();
+    let mut peer_a = Networking::start(&peer_a_cfg).await.unwrap();
     let _peer_clone = peer_a.clone();
 
     let peer_b_cfg = NetworkingConfig {
-        port: 8084,
-        bootstrap_nodes: vec![format!("/ip4/127.0.0.1/udp/8080/quic-v1/p2p/{}", bootstrap_id_clone_clone.clone())],
+        port: 0,
+        bootstrap_nodes: vec![format!("{}/p2p/{}", bootstrap_addr, bootstrap_id)],
         enable_relay_server: false,
         enable_kdht: true,
         enable_mdns: false,
@@ -76,12 +81,14 @@ async fn main() {
         name: "test-concurrent/peer-b".to_string(),
         enable_websocket: false,
         enable_webrtc: false,
+        transport: TransportMode::Memory,
+        ..Default::default()
     };
-    let mut peer_b = Networking::new(&peer_b_cfg).unwrap();
+    let mut peer_b = Networking::start(&peer_b_cfg).await.unwrap();
 
     let peer_c_cfg = NetworkingConfig {
-        port: 8086,
-        bootstrap_nodes: vec![format!("/ip4/127.0.0.1/udp/8080/quic-v1/p2p/{}", bootstrap_id_clone_clone.clone())],
+        port: 0,
+        bootstrap_nodes: vec![format!("{}/p2p/{}", bootstrap_addr, bootstrap_id)],
         enable_relay_server: false,
         enable_kdht: true,
         enable_mdns: false,
@@ -91,10 +98,21 @@ async fn main() {
         name: "test-concurrent/peer-c".to_string(),
         enable_websocket: false,
         enable_webrtc: false,
+        transport: TransportMode::Memory,
+        ..Default::default()
     };
-    let mut peer_c = Networking::new(&peer_c_cfg).unwrap();
+    let mut peer_c = Networking::start(&peer_c_cfg).await.unwrap();
 
-    sleep(Duration::from_millis(3000)).await;
+    let mut peers = HashSet::from([peer_a.id.clone(), peer_b.id.clone(), peer_c.id.clone()]);
+    while !peers.is_empty() {
+        match bootstrap_events.next().await {
+            Some(Ok(Event::PeerConnected { peer_id, .. })) => {
+                peers.remove(&peer_id.to_string());
+            }
+            Some(_) => {}
+            None => panic!("Bootstrap: event stream closed"),
+        }
+    }
     tokio::spawn(async move {
         // sleep(Duration::from_millis(500)).await;
         println!("{}: Sending message", peer_b.id);
@@ -22,6 +22,32 @@ typedef struct {
 
 typedef struct psm_posemesh_networking_context psm_posemesh_networking_context_t;
 
+// Reason of the last failure reported to a send_message callback on the calling thread, see
+// psm_posemesh_networking_get_last_error. The codes are stable, new errors get new codes.
+#define PSM_POSEMESH_NETWORKING_ERROR_NONE 0
+#define PSM_POSEMESH_NETWORKING_ERROR_UNKNOWN 1
+#define PSM_POSEMESH_NETWORKING_ERROR_DIAL_FAILURE 2
+#define PSM_POSEMESH_NETWORKING_ERROR_NO_ADDRESSES 3
+#define PSM_POSEMESH_NETWORKING_ERROR_TIMEOUT 4
+#define PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_NOT_SUPPORTED 5
+#define PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_ALREADY_REGISTERED 6
+#define PSM_POSEMESH_NETWORKING_ERROR_STREAM_RESET 7
+#define PSM_POSEMESH_NETWORKING_ERROR_CHANNEL_CLOSED 8
+#define PSM_POSEMESH_NETWORKING_ERROR_INVALID_PEER_ID 9
+#define PSM_POSEMESH_NETWORKING_ERROR_INVALID_PROTOCOL 10
+#define PSM_POSEMESH_NETWORKING_ERROR_INVALID_ADDRESS 11
+#define PSM_POSEMESH_NETWORKING_ERROR_SUBSCRIPTION 12
+#define PSM_POSEMESH_NETWORKING_ERROR_PUBLISH 13
+#define PSM_POSEMESH_NETWORKING_ERROR_LISTEN 14
+#define PSM_POSEMESH_NETWORKING_ERROR_LISTEN_FAILED 15
+#define PSM_POSEMESH_NETWORKING_ERROR_TRANSPORT 16
+#define PSM_POSEMESH_NETWORKING_ERROR_IO 17
+#define PSM_POSEMESH_NETWORKING_ERROR_RPC 18
+#define PSM_POSEMESH_NETWORKING_ERROR_INVALID_MESSAGE 19
+#define PSM_POSEMESH_NETWORKING_ERROR_DHT 20
+#define PSM_POSEMESH_NETWORKING_ERROR_KEY 21
+#define PSM_POSEMESH_NETWORKING_ERROR_INVALID_CONFIG 22
+
 #if defined(__cplusplus)
 extern "C" {
 #endif
@@ -46,6 +72,16 @@ extern "C" {
     }
 #endif
 
+// Code of the error that made send_message fail, valid inside a callback called with status 0.
+// Emscripten builds don't report the reason and always return PSM_POSEMESH_NETWORKING_ERROR_UNKNOWN.
+#if !defined(__EMSCRIPTEN__)
+    uint8_t psm_posemesh_networking_get_last_error(void);
+#else
+    static uint8_t psm_posemesh_networking_get_last_error(void) {
+        return PSM_POSEMESH_NETWORKING_ERROR_UNKNOWN;
+    }
+#endif
+
 #if !defined(__EMSCRIPTEN__)
     psm_posemesh_networking_context_t* psm_posemesh_networking_context_create(const psm_posemesh_networking_config_t* config);
 #else
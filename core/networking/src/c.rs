@@ -1,10 +1,72 @@
-use crate::libp2p::{Networking, NetworkingConfig};
+use crate::{error::NetworkingError, libp2p::{Networking, NetworkingConfig}};
 use crate::binding_helper::{posemesh_networking_get_commit_id, posemesh_networking_context_destroy};
 use std::os::raw::{c_char, c_uchar, c_void, c_uint};
-use std::ffi::CStr;
+use std::{cell::Cell, ffi::CStr};
 use std::slice;
 use runtime::get_runtime;
 
+// Reason of the last failure reported to a send_message callback on the calling thread, see
+// psm_posemesh_networking_get_last_error. The codes are stable, new errors get new codes.
+pub const PSM_POSEMESH_NETWORKING_ERROR_NONE: u8 = 0;
+#[allow(dead_code)] // only reported by the wasm binding
+pub const PSM_POSEMESH_NETWORKING_ERROR_UNKNOWN: u8 = 1;
+pub const PSM_POSEMESH_NETWORKING_ERROR_DIAL_FAILURE: u8 = 2;
+pub const PSM_POSEMESH_NETWORKING_ERROR_NO_ADDRESSES: u8 = 3;
+pub const PSM_POSEMESH_NETWORKING_ERROR_TIMEOUT: u8 = 4;
+pub const PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_NOT_SUPPORTED: u8 = 5;
+pub const PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_ALREADY_REGISTERED: u8 = 6;
+pub const PSM_POSEMESH_NETWORKING_ERROR_STREAM_RESET: u8 = 7;
+pub const PSM_POSEMESH_NETWORKING_ERROR_CHANNEL_CLOSED: u8 = 8;
+pub const PSM_POSEMESH_NETWORKING_ERROR_INVALID_PEER_ID: u8 = 9;
+pub const PSM_POSEMESH_NETWORKING_ERROR_INVALID_PROTOCOL: u8 = 10;
+pub const PSM_POSEMESH_NETWORKING_ERROR_INVALID_ADDRESS: u8 = 11;
+pub const PSM_POSEMESH_NETWORKING_ERROR_SUBSCRIPTION: u8 = 12;
+pub const PSM_POSEMESH_NETWORKING_ERROR_PUBLISH: u8 = 13;
+pub const PSM_POSEMESH_NETWORKING_ERROR_LISTEN: u8 = 14;
+pub const PSM_POSEMESH_NETWORKING_ERROR_LISTEN_FAILED: u8 = 15;
+pub const PSM_POSEMESH_NETWORKING_ERROR_TRANSPORT: u8 = 16;
+pub const PSM_POSEMESH_NETWORKING_ERROR_IO: u8 = 17;
+pub const PSM_POSEMESH_NETWORKING_ERROR_RPC: u8 = 18;
+pub const PSM_POSEMESH_NETWORKING_ERROR_INVALID_MESSAGE: u8 = 19;
+pub const PSM_POSEMESH_NETWORKING_ERROR_DHT: u8 = 20;
+pub const PSM_POSEMESH_NETWORKING_ERROR_KEY: u8 = 21;
+pub const PSM_POSEMESH_NETWORKING_ERROR_INVALID_CONFIG: u8 = 22;
+
+fn error_code(error: &NetworkingError) -> u8 {
+    match error {
+        NetworkingError::DialFailure(_) => PSM_POSEMESH_NETWORKING_ERROR_DIAL_FAILURE,
+        NetworkingError::NoAddresses(_) => PSM_POSEMESH_NETWORKING_ERROR_NO_ADDRESSES,
+        NetworkingError::Timeout => PSM_POSEMESH_NETWORKING_ERROR_TIMEOUT,
+        NetworkingError::ProtocolNotSupported(_) => PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_NOT_SUPPORTED,
+        NetworkingError::ProtocolAlreadyRegistered(_) => PSM_POSEMESH_NETWORKING_ERROR_PROTOCOL_ALREADY_REGISTERED,
+        NetworkingError::StreamReset => PSM_POSEMESH_NETWORKING_ERROR_STREAM_RESET,
+        NetworkingError::ChannelClosed => PSM_POSEMESH_NETWORKING_ERROR_CHANNEL_CLOSED,
+        NetworkingError::InvalidPeerId(_) => PSM_POSEMESH_NETWORKING_ERROR_INVALID_PEER_ID,
+        NetworkingError::InvalidProtocol(_) => PSM_POSEMESH_NETWORKING_ERROR_INVALID_PROTOCOL,
+        NetworkingError::InvalidAddress(_) => PSM_POSEMESH_NETWORKING_ERROR_INVALID_ADDRESS,
+        NetworkingError::Subscription(_) => PSM_POSEMESH_NETWORKING_ERROR_SUBSCRIPTION,
+        NetworkingError::Publish(_) => PSM_POSEMESH_NETWORKING_ERROR_PUBLISH,
+        NetworkingError::Listen(_) => PSM_POSEMESH_NETWORKING_ERROR_LISTEN,
+        NetworkingError::ListenFailed(_) => PSM_POSEMESH_NETWORKING_ERROR_LISTEN_FAILED,
+        NetworkingError::Transport(_) => PSM_POSEMESH_NETWORKING_ERROR_TRANSPORT,
+        NetworkingError::Io(_) => PSM_POSEMESH_NETWORKING_ERROR_IO,
+        NetworkingError::Rpc(_) => PSM_POSEMESH_NETWORKING_ERROR_RPC,
+        NetworkingError::InvalidMessage(_) => PSM_POSEMESH_NETWORKING_ERROR_INVALID_MESSAGE,
+        NetworkingError::Dht(_) => PSM_POSEMESH_NETWORKING_ERROR_DHT,
+        NetworkingError::Key(_) => PSM_POSEMESH_NETWORKING_ERROR_KEY,
+        NetworkingError::InvalidConfig(_) => PSM_POSEMESH_NETWORKING_ERROR_INVALID_CONFIG,
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: Cell<u8> = const { Cell::new(PSM_POSEMESH_NETWORKING_ERROR_NONE) };
+}
+
+// Callbacks run on the runtime threads, so the code is set on the thread that calls them.
+fn set_last_error(code: u8) {
+    LAST_ERROR.with(|last_error| last_error.set(code));
+}
+
 #[repr(C)]
 pub struct Config {
     pub bootstraps: *const c_char, // a list of bootstrap nodes separated by comma
@@ -64,6 +126,7 @@ pub fn to_rust(config: &Config) -> NetworkingConfig {
         name: name.to_string(),
         enable_websocket: false,
         enable_webrtc: false,
+        ..Default::default()
     }
 }
 
@@ -73,14 +136,22 @@ pub extern "C" fn psm_posemesh_networking_context_create(config: *const Config)
     assert!(!config.is_null(), "psm_posemesh_networking_context_create(): config is null");
     let config = unsafe { &*config };
     let config = to_rust(&config);
-    get_runtime().block_on(async move {
-        let networking = Networking::new(&config).expect("psm_posemesh_networking_context_create(): failed to create networking context");
-        return Box::into_raw(Box::new(networking))
-    })
+    match get_runtime().block_on(Networking::start(&config)) {
+        Ok(networking) => Box::into_raw(Box::new(networking)),
+        Err(error) => {
+            eprintln!("psm_posemesh_networking_context_create(): {} ({:?})", error.kind(), error);
+            std::ptr::null_mut()
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn psm_posemesh_networking_context_destroy(context: *mut Networking) {
+    assert!(!context.is_null(), "psm_posemesh_networking_context_destroy(): context is null");
+    let networking = unsafe { &*context };
+    if let Err(error) = get_runtime().block_on(networking.shutdown()) {
+        eprintln!("psm_posemesh_networking_context_destroy(): {:?}", error);
+    }
     posemesh_networking_context_destroy(context);
 }
 
@@ -98,6 +169,12 @@ pub extern "C" fn psm_posemesh_networking_context_send_message(
     send_message(context, message, message_size, peer_id, protocol, user_data, timeout, callback)
 }
 
+/// Code of the error that made send_message fail, valid inside a callback called with status 0.
+#[no_mangle]
+pub extern "C" fn psm_posemesh_networking_get_last_error() -> u8 {
+    LAST_ERROR.with(|last_error| last_error.get())
+}
+
 #[no_mangle]
 pub extern "C" fn psm_posemesh_networking_get_commit_id(buffer: *mut c_char, size: *mut c_uint) {
     assert!(!buffer.is_null(), "psm_posemesh_networking_get_commit_id(): buffer is null");
@@ -166,7 +243,7 @@ fn send_message(
             match sender.send(message, peer_id, protocol, timeout).await {
                 Ok(_) => { },
                 Err(error) => {
-                    eprintln!("send_message(): {:?}", error);
+                    eprintln!("send_message(): {} ({:?})", error.kind(), error);
 
                     #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos"))]
                     eprintln!("Failed to send message: Apple platforms require 'com.apple.security.network.client' entitlement set to YES.");
@@ -179,15 +256,17 @@ fn send_message(
             match sender.send(message, peer_id, protocol, timeout).await {
                 Ok(_) => {
                     let user_data = user_data_safe as *mut c_void;
+                    set_last_error(PSM_POSEMESH_NETWORKING_ERROR_NONE);
                     callback(1, user_data);
                 },
                 Err(error) => {
-                    eprintln!("send_message(): {:?}", error);
+                    eprintln!("send_message(): {} ({:?})", error.kind(), error);
 
                     #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos"))]
                     eprintln!("Failed to send message: Apple platforms require 'com.apple.security.network.client' entitlement set to YES.");
 
                     let user_data = user_data_safe as *mut c_void;
+                    set_last_error(error_code(&error));
                     callback(0, user_data);
                 }
             }
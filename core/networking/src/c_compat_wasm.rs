@@ -1,6 +1,7 @@
+use futures::FutureExt;
 use wasm_bindgen::prelude::*;
 use crate::{binding_helper::{posemesh_networking_context_destroy, posemesh_networking_get_commit_id}, libp2p::{Networking, NetworkingConfig}};
-use wasm_bindgen_futures::{future_to_promise, js_sys::{Promise, Error}};
+use wasm_bindgen_futures::{future_to_promise, spawn_local, js_sys::{Promise, Error}};
 
 #[wasm_bindgen(getter_with_clone)]
 #[allow(non_snake_case)]
@@ -44,14 +45,33 @@ pub fn posemeshNetworkingContextCreate(config: Config) -> *mut Networking {
         port: 0,
         enable_websocket: true,
         enable_webrtc: true,
+        ..Default::default()
     };
-    let networking = Networking::new(&config).expect("posemeshNetworkingContextCreate(): failed to create networking context");
-    Box::into_raw(Box::new(networking))
+    // The C API returns the context synchronously. A browser node has no listener to wait for, so
+    // `start` is ready on its first poll.
+    match Networking::start(&config).now_or_never() {
+        Some(Ok(networking)) => Box::into_raw(Box::new(networking)),
+        Some(Err(error)) => {
+            eprintln!("posemeshNetworkingContextCreate(): {:?}", error);
+            std::ptr::null_mut()
+        }
+        None => {
+            eprintln!("posemeshNetworkingContextCreate(): networking didn't start synchronously");
+            std::ptr::null_mut()
+        }
+    }
 }
 
 #[wasm_bindgen]
 #[allow(non_snake_case)]
 pub fn posemeshNetworkingContextDestroy(context: *mut Networking) {
+    assert!(!context.is_null(), "posemeshNetworkingContextDestroy(): context is null");
+    let networking = unsafe { &*context }.clone();
+    spawn_local(async move {
+        if let Err(error) = networking.shutdown().await {
+            eprintln!("posemeshNetworkingContextDestroy(): {:?}", error);
+        }
+    });
     posemesh_networking_context_destroy(context);
 }
 
@@ -74,7 +94,9 @@ pub fn posemeshNetworkingContextSendMessage(context: *mut Networking, message: V
             Ok(_) => { Ok(JsValue::from(true)) },
             Err(error) => {
                 eprintln!("posemesh_networking_context_send_message(): {:?}", error);
-                Err(JsValue::from(Error::new(error.to_string().as_str())))
+                let js_error = Error::new(error.to_string().as_str());
+                js_error.set_name(error.kind());
+                Err(JsValue::from(js_error))
             }
         }
     });
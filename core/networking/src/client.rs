@@ -1,40 +1,50 @@
-use libp2p::{PeerId, Stream, StreamProtocol};
+use libp2p::{kad::Quorum, Multiaddr, PeerId, Stream, StreamProtocol};
 use libp2p_stream::IncomingStreams;
 use utils;
-use std::{error::Error, time::Duration};
-use futures::{channel::{mpsc, oneshot}, SinkExt};
-use std::str::FromStr;
+use std::time::Duration;
+use web_time::Instant;
+use futures::{channel::{mpsc, oneshot}, stream::FusedStream, SinkExt, StreamExt};
+use std::{pin::Pin, str::FromStr, task::{Context, Poll}};
+use crate::{error::NetworkingError, event::{PubsubMessage, Reachability, TopicValidator, DEFAULT_EVENT_BUFFER_SIZE}, libp2p::{NodeResources, PeerInfo}, record::DhtRecord};
 #[cfg(not(target_family = "wasm"))]
 use tokio::time::sleep;
 #[cfg(target_family = "wasm")]
 use utils::sleep;
 
-async fn retry_send(mut command_sender: mpsc::Sender<Command>, message: Vec<u8>, peer_id: PeerId, protocol: StreamProtocol, timeout: u32, last: bool) -> Result<Stream, Box<dyn Error + Send + Sync>> {
-    let (sender, receiver) = oneshot::channel::<Result<Stream, Box<dyn Error + Send + Sync>>>();
+async fn retry_send(mut command_sender: mpsc::Sender<Command>, message: Vec<u8>, peer_id: PeerId, protocol: StreamProtocol, timeout: u32, last: bool) -> Result<Stream, NetworkingError> {
+    let started = Instant::now();
+    let (sender, receiver) = oneshot::channel::<Result<Stream, NetworkingError>>();
     command_sender
         .send(Command::Send { message: message.clone(), peer_id: peer_id.clone(), protocol: protocol.clone(), response: sender })
-        .await
-        .map_err(|e| Box::new(e))?;
+        .await?;
 
     let result = utils::timeout(Duration::from_millis(timeout as u64), async move {
         match receiver.await {
             Ok(result) => result,
-            Err(e) => Err(Box::new(e) as Box<dyn Error + Send + Sync>),
+            Err(e) => Err(NetworkingError::from(e)),
         }
-    }).await?;
+    }).await.map_err(|_| NetworkingError::Timeout)?;
 
     match result {
         Ok(s) => Ok(s),
-        Err(e) => {
-            if let Some(dial_error) = e.downcast_ref::<libp2p::swarm::DialError>() {
-                if matches!(dial_error, libp2p::swarm::DialError::NoAddresses) && !last {
-                    tracing::warn!("find address the last time: {:?}", dial_error);
-                    sleep(Duration::from_millis(500)).await;
-                    return Box::pin(retry_send(command_sender, message, peer_id, protocol, timeout, true)).await;
+        Err(NetworkingError::NoAddresses(_)) if !last => {
+            tracing::warn!("find address the last time: {}", peer_id);
+            sleep(Duration::from_millis(500)).await;
+            // the retry gets what is left of the timeout, not a new one
+            let timeout = if timeout == 0 {
+                0
+            } else {
+                let elapsed = u32::try_from(started.elapsed().as_millis()).unwrap_or(u32::MAX);
+                match timeout.checked_sub(elapsed) {
+                    Some(left) if left > 0 => left,
+                    _ => return Err(NetworkingError::Timeout),
                 }
-            }
+            };
+            Box::pin(retry_send(command_sender, message, peer_id, protocol, timeout, true)).await
+        },
+        Err(e) => {
             tracing::error!("send error: {:?}", e);
-            return Err(e);
+            Err(e)
         },
     }
 }
@@ -49,76 +59,456 @@ impl Client {
         Self { sender }
     }
 
-    
+
     // timeout is in milliseconds
-    pub async fn send(&mut self, message: Vec<u8>, peer_id: String, protocol: String, timeout: u32) -> Result<Stream, Box<dyn Error + Send + Sync>> {
-        let peer_id = PeerId::from_str(&peer_id).map_err(|e| Box::new(e))?;
-        let pro = StreamProtocol::try_from_owned(protocol).map_err(|e| Box::new(e))?; 
-        
+    pub async fn send(&mut self, message: Vec<u8>, peer_id: String, protocol: String, timeout: u32) -> Result<Stream, NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let pro = StreamProtocol::try_from_owned(protocol.clone()).map_err(|_| NetworkingError::InvalidProtocol(protocol))?;
+
         retry_send(self.sender.clone(), message, peer_id, pro, timeout, false).await
     }
 
-    pub async fn set_stream_handler(&mut self, protocol: String) -> Result<IncomingStreams, Box<dyn Error + Send + Sync>> {
-        let (sender, receiver) = oneshot::channel::<Result<IncomingStreams, Box<dyn Error + Send + Sync>>>();
-        let pro = StreamProtocol::try_from_owned(protocol).map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+    pub async fn set_stream_handler(&mut self, protocol: String) -> Result<IncomingStreams, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<IncomingStreams, NetworkingError>>();
+        let pro = StreamProtocol::try_from_owned(protocol.clone()).map_err(|_| NetworkingError::InvalidProtocol(protocol))?;
         self.sender
             .send(Command::SetStreamHandler { protocol: pro, sender })
-            .await
-            .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            .await?;
 
-        match receiver.await {
-            Ok(result) => result,
-            Err(e) => Err(Box::new(e)), 
-        }
+        receiver.await?
     }
 
-    pub async fn subscribe(&mut self, topic: String) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (resp, req) = oneshot::channel::<Box<dyn Error + Send + Sync>>();
+    /// Subscribes to `topic` and returns the stream of messages published on it.
+    /// Several subscriptions to the same topic each receive every message.
+    pub async fn subscribe(&mut self, topic: String) -> Result<Subscription, NetworkingError> {
+        let (sender, receiver) = mpsc::channel::<PubsubMessage>(DEFAULT_EVENT_BUFFER_SIZE);
+        let (resp, req) = oneshot::channel::<Result<u64, NetworkingError>>();
         self.sender
-            .send(Command::Subscribe { topic, resp })
-            .await
-            .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            .send(Command::Subscribe { topic: topic.clone(), sender, resp })
+            .await?;
 
-        if let Ok(e) = req.await {
-            return Err(e);
-        }
+        let id = req.await??;
+        Ok(Subscription { topic, id, receiver, commands: self.sender.clone() })
+    }
 
+    /// Leaves `topic` and ends every subscription to it.
+    pub async fn unsubscribe(&mut self, topic: String) -> Result<(), NetworkingError> {
+        let (resp, req) = oneshot::channel::<Result<(), NetworkingError>>();
+        self.sender
+            .send(Command::Unsubscribe { topic, id: None, resp: Some(resp) })
+            .await?;
+
+        req.await?
+    }
+
+    /// Validates every message received on `topic` before it is delivered or forwarded,
+    /// replacing the previous validator. Without a validator messages are accepted.
+    /// The validator is dropped once the node leaves the topic.
+    pub async fn set_topic_validator(&mut self, topic: String, validator: TopicValidator) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::SetTopicValidator { topic, validator: Some(validator), sender })
+            .await?;
+
+        receiver.await?;
         Ok(())
     }
 
-    pub async fn publish(&mut self, topic: String, message: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (sender, receiver) = oneshot::channel::<Result<(), Box<dyn Error + Send + Sync>>>();
+    pub async fn remove_topic_validator(&mut self, topic: String) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::SetTopicValidator { topic, validator: None, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    pub async fn publish(&mut self, topic: String, message: Vec<u8>) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<(), NetworkingError>>();
         self.sender
             .send(Command::Publish { topic, message, sender })
-            .await
-            .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>)?;
+            .await?;
 
-        if let Ok(Err(e)) = receiver.await {
-            return Err(e);
-        }
+        receiver.await?
+    }
+
+    /// Closes all connections to the peer and refuses new ones until it is unbanned.
+    pub async fn ban_peer(&mut self, peer_id: String) -> Result<(), NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::BanPeer { peer_id, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    pub async fn unban_peer(&mut self, peer_id: String) -> Result<(), NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::UnbanPeer { peer_id, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    /// Announces in the DHT that this node provides `capability`, e.g. a protocol it serves.
+    /// The record is republished by Kademlia until [`Client::stop_providing`] is called.
+    pub async fn provide(&mut self, capability: String) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<(), NetworkingError>>();
+        self.sender
+            .send(Command::Provide { capability, sender })
+            .await?;
+
+        receiver.await?
+    }
+
+    /// Stops announcing `capability`. Remote peers forget the record once it expires.
+    pub async fn stop_providing(&mut self, capability: String) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<(), NetworkingError>>();
+        self.sender
+            .send(Command::StopProviding { capability, sender })
+            .await?;
+
+        receiver.await?
+    }
+
+    /// Looks up the peers that announced `capability` across the whole DHT.
+    pub async fn find_providers(&mut self, capability: String) -> Result<Vec<String>, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<Vec<PeerId>, NetworkingError>>();
+        self.sender
+            .send(Command::FindProviders { capability, sender })
+            .await?;
+
+        let providers = receiver.await??;
+        Ok(providers.iter().map(|peer_id| peer_id.to_string()).collect())
+    }
+
+    /// Publishes `value` under `key` in the DHT, signed with the node's key, and succeeds once
+    /// `quorum` peers stored it. The record expires after `ttl`, or after the Kademlia default
+    /// of 36 hours when None, and is republished by this node until then.
+    pub async fn put_record(&mut self, key: String, value: Vec<u8>, ttl: Option<Duration>, quorum: Quorum) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<(), NetworkingError>>();
+        self.sender
+            .send(Command::PutRecord { key, value, ttl, quorum, sender })
+            .await?;
+
+        receiver.await?
+    }
+
+    /// Looks up `key` in the DHT and returns the record once `quorum` peers returned the same
+    /// value signed by the same publisher. Records without a valid signature are ignored.
+    /// `Quorum::Majority` and `Quorum::All` count the peers that returned the record when the
+    /// query finished, rather than `K_VALUE`, so they also work on a small network.
+    pub async fn get_record(&mut self, key: String, quorum: Quorum) -> Result<DhtRecord, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<DhtRecord, NetworkingError>>();
+        self.sender
+            .send(Command::GetRecord { key, quorum, sender })
+            .await?;
+
+        receiver.await?
+    }
+
+    /// Replaces the resources this node announces and announces them right away,
+    /// e.g. when its load changed.
+    pub async fn set_resources(&mut self, resources: NodeResources) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::SetResources { resources, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    /// Connects to `address`, which may end with the expected `/p2p/<peer id>`, and returns
+    /// the id of the peer once the connection is established.
+    pub async fn dial(&mut self, address: String) -> Result<String, NetworkingError> {
+        let address = Multiaddr::from_str(&address).map_err(|e| NetworkingError::InvalidAddress(format!("{address}: {e}")))?;
+        let (sender, receiver) = oneshot::channel::<Result<PeerId, NetworkingError>>();
+        self.sender
+            .send(Command::Dial { address, sender })
+            .await?;
+
+        let peer_id = receiver.await??;
+        Ok(peer_id.to_string())
+    }
+
+    /// Closes all connections to the peer. Bootstrap and relay nodes are dialed again later.
+    pub async fn disconnect(&mut self, peer_id: String) -> Result<(), NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::Disconnect { peer_id, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    /// Remembers an address of the peer learned out-of-band, e.g. from a QR code or a config file.
+    /// [`Client::send`] then dials the peer directly instead of looking it up in the DHT.
+    pub async fn add_peer_address(&mut self, peer_id: String, address: String) -> Result<(), NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let address = Multiaddr::from_str(&address).map_err(|e| NetworkingError::InvalidAddress(format!("{address}: {e}")))?;
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::AddPeerAddress { peer_id, address, sender })
+            .await?;
+
+        receiver.await?;
+        Ok(())
+    }
+
+    /// Returns the key of the node encoded by [`crate::keys::export`], encrypted when a passphrase
+    /// is given. Pass it as NetworkingConfig::private_key to start another node with this identity.
+    pub async fn export_key(&mut self, passphrase: Option<String>) -> Result<Vec<u8>, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Result<Vec<u8>, NetworkingError>>();
+        self.sender
+            .send(Command::ExportKey { passphrase, sender })
+            .await?;
+
+        receiver.await?
+    }
+
+    /// Returns the reachability of the node. It stays unknown in the browser, which isn't probed.
+    pub async fn nat_status(&mut self) -> Result<Reachability, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Reachability>();
+        self.sender
+            .send(Command::NatStatus { sender })
+            .await?;
+
+        Ok(receiver.await?)
+    }
+
+    /// Returns what is known about `peer_id`, or None when it isn't connected.
+    pub async fn peer_info(&mut self, peer_id: String) -> Result<Option<PeerInfo>, NetworkingError> {
+        let peer_id = PeerId::from_str(&peer_id).map_err(|_| NetworkingError::InvalidPeerId(peer_id))?;
+        let (sender, receiver) = oneshot::channel::<Option<PeerInfo>>();
+        self.sender
+            .send(Command::PeerInfo { peer_id, sender })
+            .await?;
+
+        Ok(receiver.await?)
+    }
+
+    pub async fn connected_peers(&mut self) -> Result<Vec<PeerInfo>, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Vec<PeerInfo>>();
+        self.sender
+            .send(Command::ConnectedPeers { sender })
+            .await?;
+
+        Ok(receiver.await?)
+    }
+
+    /// Local addresses the node is listening on. Empty until the listeners are bound.
+    pub async fn listen_addresses(&mut self) -> Result<Vec<Multiaddr>, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Vec<Multiaddr>>();
+        self.sender
+            .send(Command::ListenAddresses { sender })
+            .await?;
+
+        Ok(receiver.await?)
+    }
+
+    /// Addresses other peers can reach the node on, as confirmed by AutoNAT or added manually.
+    pub async fn external_addresses(&mut self) -> Result<Vec<Multiaddr>, NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<Vec<Multiaddr>>();
+        self.sender
+            .send(Command::ExternalAddresses { sender })
+            .await?;
+
+        Ok(receiver.await?)
+    }
+
+    pub async fn shutdown(&mut self) -> Result<(), NetworkingError> {
+        let (sender, receiver) = oneshot::channel::<()>();
+        self.sender
+            .send(Command::Shutdown { sender })
+            .await?;
+
+        receiver.await?;
         Ok(())
     }
 }
 
+/// Messages published on a single topic. Dropping it unsubscribes; the node leaves
+/// the topic once its last subscription is gone.
+#[derive(Debug)]
+pub struct Subscription {
+    topic: String,
+    id: u64,
+    receiver: mpsc::Receiver<PubsubMessage>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl futures::Stream for Subscription {
+    type Item = PubsubMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl FusedStream for Subscription {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // If the command channel is full the networking loop still notices the closed
+        // receiver the next time a message arrives on the topic.
+        let _ = self.commands.try_send(Command::Unsubscribe { topic: self.topic.clone(), id: Some(self.id), resp: None });
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
     Send {
         message: Vec<u8>,
         peer_id: PeerId,
         protocol: StreamProtocol,
-        response: oneshot::Sender<Result<Stream, Box<dyn Error + Send + Sync>>>,
+        response: oneshot::Sender<Result<Stream, NetworkingError>>,
     },
     SetStreamHandler {
         protocol: StreamProtocol,
-        sender: oneshot::Sender<Result<IncomingStreams, Box<dyn Error + Send + Sync>>>,
+        sender: oneshot::Sender<Result<IncomingStreams, NetworkingError>>,
     },
     Publish {
         topic: String,
         message: Vec<u8>,
-        sender: oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
+        sender: oneshot::Sender<Result<(), NetworkingError>>,
     },
     Subscribe {
         topic: String,
-        resp: oneshot::Sender<Box<dyn Error + Send + Sync>>,
+        sender: mpsc::Sender<PubsubMessage>,
+        resp: oneshot::Sender<Result<u64, NetworkingError>>,
+    },
+    Unsubscribe {
+        topic: String,
+        // None drops every subscription to the topic
+        id: Option<u64>,
+        resp: Option<oneshot::Sender<Result<(), NetworkingError>>>,
+    },
+    SetTopicValidator {
+        topic: String,
+        validator: Option<TopicValidator>,
+        sender: oneshot::Sender<()>,
+    },
+    BanPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+    UnbanPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+    Provide {
+        capability: String,
+        sender: oneshot::Sender<Result<(), NetworkingError>>,
+    },
+    StopProviding {
+        capability: String,
+        sender: oneshot::Sender<Result<(), NetworkingError>>,
+    },
+    FindProviders {
+        capability: String,
+        sender: oneshot::Sender<Result<Vec<PeerId>, NetworkingError>>,
+    },
+    PutRecord {
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        quorum: Quorum,
+        sender: oneshot::Sender<Result<(), NetworkingError>>,
+    },
+    GetRecord {
+        key: String,
+        quorum: Quorum,
+        sender: oneshot::Sender<Result<DhtRecord, NetworkingError>>,
+    },
+    SetResources {
+        resources: NodeResources,
+        sender: oneshot::Sender<()>,
+    },
+    Dial {
+        address: Multiaddr,
+        sender: oneshot::Sender<Result<PeerId, NetworkingError>>,
+    },
+    Disconnect {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+    AddPeerAddress {
+        peer_id: PeerId,
+        address: Multiaddr,
+        sender: oneshot::Sender<()>,
+    },
+    ExportKey {
+        passphrase: Option<String>,
+        sender: oneshot::Sender<Result<Vec<u8>, NetworkingError>>,
+    },
+    NatStatus {
+        sender: oneshot::Sender<Reachability>,
+    },
+    PeerInfo {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Option<PeerInfo>>,
+    },
+    ConnectedPeers {
+        sender: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    ListenAddresses {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    ExternalAddresses {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+}
+
+impl Command {
+    /// Name of the command, used to label metrics.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Command::Send { .. } => "Send",
+            Command::SetStreamHandler { .. } => "SetStreamHandler",
+            Command::Publish { .. } => "Publish",
+            Command::Subscribe { .. } => "Subscribe",
+            Command::Unsubscribe { .. } => "Unsubscribe",
+            Command::SetTopicValidator { .. } => "SetTopicValidator",
+            Command::BanPeer { .. } => "BanPeer",
+            Command::UnbanPeer { .. } => "UnbanPeer",
+            Command::Provide { .. } => "Provide",
+            Command::StopProviding { .. } => "StopProviding",
+            Command::FindProviders { .. } => "FindProviders",
+            Command::PutRecord { .. } => "PutRecord",
+            Command::GetRecord { .. } => "GetRecord",
+            Command::SetResources { .. } => "SetResources",
+            Command::Dial { .. } => "Dial",
+            Command::Disconnect { .. } => "Disconnect",
+            Command::AddPeerAddress { .. } => "AddPeerAddress",
+            Command::ExportKey { .. } => "ExportKey",
+            Command::NatStatus { .. } => "NatStatus",
+            Command::PeerInfo { .. } => "PeerInfo",
+            Command::ConnectedPeers { .. } => "ConnectedPeers",
+            Command::ListenAddresses { .. } => "ListenAddresses",
+            Command::ExternalAddresses { .. } => "ExternalAddresses",
+            Command::Shutdown { .. } => "Shutdown",
+        }
     }
 }
@@ -0,0 +1,167 @@
+use libp2p::{gossipsub, swarm::DialError, Multiaddr, PeerId, StreamProtocol, TransportError};
+use libp2p_stream::OpenStreamError;
+use crate::rpc::RpcError;
+use std::{error::Error, fmt, io};
+
+#[derive(Debug)]
+pub enum NetworkingError {
+    /// Dialing the remote peer failed for a reason other than missing addresses.
+    DialFailure(DialError),
+    /// No addresses are known for the peer, neither locally nor in the DHT.
+    NoAddresses(PeerId),
+    Timeout,
+    /// The remote peer does not accept streams for the protocol.
+    ProtocolNotSupported(StreamProtocol),
+    /// A handler is already registered locally for the protocol.
+    ProtocolAlreadyRegistered(StreamProtocol),
+    StreamReset,
+    /// The networking loop is gone, or it dropped the request without answering.
+    ChannelClosed,
+    InvalidPeerId(String),
+    InvalidProtocol(String),
+    InvalidAddress(String),
+    Subscription(gossipsub::SubscriptionError),
+    Publish(gossipsub::PublishError),
+    Listen(TransportError<io::Error>),
+    /// Some listeners could not be bound, with the address and the error of each.
+    ListenFailed(Vec<(Multiaddr, String)>),
+    Transport(String),
+    Io(io::Error),
+    /// The remote handler answered with an error.
+    Rpc(RpcError),
+    /// A message could not be encoded, or the remote sent one that could not be decoded.
+    InvalidMessage(String),
+    /// A Kademlia query failed, or Kademlia is disabled.
+    Dht(String),
+    /// A key could not be decoded, decrypted or written.
+    Key(String),
+    /// A value of the NetworkingConfig is rejected.
+    InvalidConfig(String),
+}
+
+impl NetworkingError {
+    /// Short, stable name of the failure kind, used by the C and wasm bindings.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetworkingError::DialFailure(_) => "DialFailure",
+            NetworkingError::NoAddresses(_) => "NoAddresses",
+            NetworkingError::Timeout => "Timeout",
+            NetworkingError::ProtocolNotSupported(_) => "ProtocolNotSupported",
+            NetworkingError::ProtocolAlreadyRegistered(_) => "ProtocolAlreadyRegistered",
+            NetworkingError::StreamReset => "StreamReset",
+            NetworkingError::ChannelClosed => "ChannelClosed",
+            NetworkingError::InvalidPeerId(_) => "InvalidPeerId",
+            NetworkingError::InvalidProtocol(_) => "InvalidProtocol",
+            NetworkingError::InvalidAddress(_) => "InvalidAddress",
+            NetworkingError::Subscription(_) => "Subscription",
+            NetworkingError::Publish(_) => "Publish",
+            NetworkingError::Listen(_) => "Listen",
+            NetworkingError::ListenFailed(_) => "ListenFailed",
+            NetworkingError::Transport(_) => "Transport",
+            NetworkingError::Io(_) => "Io",
+            NetworkingError::Rpc(_) => "Rpc",
+            NetworkingError::InvalidMessage(_) => "InvalidMessage",
+            NetworkingError::Dht(_) => "Dht",
+            NetworkingError::Key(_) => "Key",
+            NetworkingError::InvalidConfig(_) => "InvalidConfig",
+        }
+    }
+}
+
+impl Error for NetworkingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NetworkingError::DialFailure(e) => Some(e),
+            NetworkingError::Subscription(e) => Some(e),
+            NetworkingError::Publish(e) => Some(e),
+            NetworkingError::Listen(e) => Some(e),
+            NetworkingError::Io(e) => Some(e),
+            NetworkingError::Rpc(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for NetworkingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkingError::DialFailure(e) => write!(f, "Dial failure: {}", e),
+            NetworkingError::NoAddresses(peer_id) => write!(f, "No addresses found for peer {}", peer_id),
+            NetworkingError::Timeout => write!(f, "Operation timed out"),
+            NetworkingError::ProtocolNotSupported(protocol) => write!(f, "Protocol not supported: {}", protocol),
+            NetworkingError::ProtocolAlreadyRegistered(protocol) => write!(f, "Protocol already registered: {}", protocol),
+            NetworkingError::StreamReset => write!(f, "Stream reset"),
+            NetworkingError::ChannelClosed => write!(f, "Networking channel closed"),
+            NetworkingError::InvalidPeerId(peer_id) => write!(f, "Invalid peer id: {}", peer_id),
+            NetworkingError::InvalidProtocol(protocol) => write!(f, "Invalid protocol: {}", protocol),
+            NetworkingError::InvalidAddress(address) => write!(f, "Invalid address: {}", address),
+            NetworkingError::Subscription(e) => write!(f, "Subscription error: {}", e),
+            NetworkingError::Publish(e) => write!(f, "Publish error: {}", e),
+            NetworkingError::Listen(e) => write!(f, "Listen error: {}", e),
+            NetworkingError::ListenFailed(failures) => {
+                write!(f, "Failed to listen on")?;
+                for (i, (address, e)) in failures.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i == 0 { "" } else { "," }, address, e)?;
+                }
+                Ok(())
+            }
+            NetworkingError::Transport(e) => write!(f, "Transport error: {}", e),
+            NetworkingError::Io(e) => write!(f, "IO error: {}", e),
+            NetworkingError::Rpc(e) => write!(f, "Remote error: {}", e),
+            NetworkingError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+            NetworkingError::Dht(e) => write!(f, "DHT error: {}", e),
+            NetworkingError::Key(e) => write!(f, "Key error: {}", e),
+            NetworkingError::InvalidConfig(e) => write!(f, "Invalid config: {}", e),
+        }
+    }
+}
+
+impl From<OpenStreamError> for NetworkingError {
+    fn from(e: OpenStreamError) -> Self {
+        match e {
+            OpenStreamError::UnsupportedProtocol(protocol) => NetworkingError::ProtocolNotSupported(protocol),
+            OpenStreamError::Io(e) => NetworkingError::from(e),
+            e => NetworkingError::Transport(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for NetworkingError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => NetworkingError::StreamReset,
+            io::ErrorKind::TimedOut => NetworkingError::Timeout,
+            _ => NetworkingError::Io(e),
+        }
+    }
+}
+
+impl From<gossipsub::SubscriptionError> for NetworkingError {
+    fn from(e: gossipsub::SubscriptionError) -> Self {
+        NetworkingError::Subscription(e)
+    }
+}
+
+impl From<gossipsub::PublishError> for NetworkingError {
+    fn from(e: gossipsub::PublishError) -> Self {
+        NetworkingError::Publish(e)
+    }
+}
+
+impl From<TransportError<io::Error>> for NetworkingError {
+    fn from(e: TransportError<io::Error>) -> Self {
+        NetworkingError::Listen(e)
+    }
+}
+
+impl From<futures::channel::mpsc::SendError> for NetworkingError {
+    fn from(_: futures::channel::mpsc::SendError) -> Self {
+        NetworkingError::ChannelClosed
+    }
+}
+
+impl From<futures::channel::oneshot::Canceled> for NetworkingError {
+    fn from(_: futures::channel::oneshot::Canceled) -> Self {
+        NetworkingError::ChannelClosed
+    }
+}
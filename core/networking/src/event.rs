@@ -1,7 +1,11 @@
-use libp2p::{gossipsub::TopicHash, PeerId};
-use std::error::Error;
+use libp2p::{gossipsub::TopicHash, Multiaddr, PeerId};
+use crate::error::NetworkingError;
+use futures::{channel::mpsc, future::BoxFuture, stream::FusedStream, Future, FutureExt, Stream, StreamExt};
+use std::{error::Error, fmt, pin::Pin, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, task::{Context, Poll}};
 
-#[derive(Debug)]
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
 pub enum Event {
     NewNodeRegistered {
         node: crate::libp2p::Node,
@@ -11,6 +15,128 @@ pub enum Event {
         message: Vec<u8>,
         from: Option<PeerId>,
     },
+    PeerConnected {
+        peer_id: PeerId,
+        address: Multiaddr,
+        // number of connections to the peer, including this one
+        num_established: u32,
+    },
+    PeerDisconnected {
+        peer_id: PeerId,
+        // number of connections to the peer that are still open
+        num_established: u32,
+        // None if the connection was closed gracefully
+        cause: Option<String>,
+    },
+    // mDNS record of a peer address has expired
+    PeerExpired {
+        peer_id: PeerId,
+        address: Multiaddr,
+    },
+    RelayReservationAccepted {
+        relay_peer_id: PeerId,
+        renewal: bool,
+    },
+    // the events below are only sent when enable_relay_server is set
+    RelayServerReservationAccepted {
+        peer_id: PeerId,
+        // the peer already had a reservation
+        renewed: bool,
+    },
+    // the peer isn't allowed, or a limit of RelayServerConfig was reached
+    RelayServerReservationDenied {
+        peer_id: PeerId,
+    },
+    RelayServerReservationExpired {
+        peer_id: PeerId,
+    },
+    RelayServerCircuitOpened {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+    },
+    RelayServerCircuitDenied {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+    },
+    RelayServerCircuitClosed {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        // None if the circuit ended without an error, e.g. when it reached its limits
+        error: Option<String>,
+    },
+    // sent when the status or its confidence changes
+    NatStatusChanged {
+        status: NatStatus,
+        confidence: usize,
+    },
+    ExternalAddressConfirmed {
+        address: Multiaddr,
+    },
+    ListenAddressAdded {
+        address: Multiaddr,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatStatus {
+    // reachable from the public internet on the given address
+    Public(Multiaddr),
+    // behind a NAT or firewall
+    Private,
+    Unknown,
+}
+
+/// Reachability of the node, as measured by AutoNAT probes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reachability {
+    pub status: NatStatus,
+    /// Probes that confirmed the status since it was last set, up to NatConfig::confidence_max.
+    /// Always 0 while the status is unknown.
+    pub confidence: usize,
+}
+
+/// A message received on a topic the node is subscribed to.
+#[derive(Debug, Clone)]
+pub struct PubsubMessage {
+    pub topic: TopicHash,
+    pub message: Vec<u8>,
+    pub from: Option<PeerId>,
+}
+
+/// Outcome of validating a pubsub message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// Deliver the message and forward it to the mesh.
+    Accept,
+    /// Drop the message and penalise the peer that forwarded it, if peer scoring is enabled.
+    Reject,
+    /// Drop the message without penalising anyone.
+    Ignore,
+}
+
+/// Decides whether a message received on a topic is delivered to subscribers and forwarded.
+/// Messages are held back until the returned future resolves.
+#[derive(Clone)]
+pub struct TopicValidator(Arc<dyn Fn(PubsubMessage) -> BoxFuture<'static, Validation> + Send + Sync>);
+
+impl TopicValidator {
+    pub fn new<F, Fut>(validator: F) -> Self
+    where
+        F: Fn(PubsubMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Validation> + Send + 'static,
+    {
+        TopicValidator(Arc::new(move |message| validator(message).boxed()))
+    }
+
+    pub(crate) fn validate(&self, message: PubsubMessage) -> BoxFuture<'static, Validation> {
+        (self.0)(message)
+    }
+}
+
+impl fmt::Debug for TopicValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TopicValidator")
+    }
 }
 
 #[derive(Debug)]
@@ -19,5 +145,108 @@ pub enum PubsubResult {
         message: Vec<u8>,
         from: Option<PeerId>,
     },
-    Err(Box<dyn Error + Send + Sync>),
+    Err(NetworkingError),
+}
+
+#[derive(Clone)]
+pub struct EventFilter(Arc<dyn Fn(&Event) -> bool + Send + Sync>);
+
+impl EventFilter {
+    pub fn new<F: Fn(&Event) -> bool + Send + Sync + 'static>(filter: F) -> Self {
+        EventFilter(Arc::new(filter))
+    }
+
+    pub fn all() -> Self {
+        EventFilter::new(|_| true)
+    }
+
+    /// Only pubsub messages received on the given topic.
+    pub fn topic(topic: String) -> Self {
+        let topic = TopicHash::from_raw(topic);
+        EventFilter::new(move |event| matches!(event, Event::PubSubMessageReceivedEvent { topic: t, .. } if *t == topic))
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventFilter")
+    }
+}
+
+/// Returned by [`EventStream`] when the subscriber fell behind and events were dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl Error for Lagged {}
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Event stream lagged behind, {} events were dropped", self.0)
+    }
+}
+
+struct Subscriber {
+    sender: mpsc::Sender<Event>,
+    filter: EventFilter,
+    lagged: Arc<AtomicU64>,
+}
+
+/// Fans out networking events to every subscribed [`EventStream`].
+/// Publishing never blocks: a subscriber whose buffer is full misses the event and is told so.
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self, filter: EventFilter, buffer: usize) -> EventStream {
+        let (sender, receiver) = mpsc::channel::<Event>(buffer);
+        let lagged = Arc::new(AtomicU64::new(0));
+        self.subscribers.lock().unwrap().push(Subscriber { sender, filter, lagged: lagged.clone() });
+        EventStream { receiver, lagged }
+    }
+
+    pub(crate) fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| {
+            if !subscriber.filter.matches(&event) {
+                return !subscriber.sender.is_closed();
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(e) if e.is_full() => {
+                    subscriber.lagged.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+/// An independent view on the networking events. Dropping it unsubscribes.
+pub struct EventStream {
+    receiver: mpsc::Receiver<Event>,
+    lagged: Arc<AtomicU64>,
+}
+
+impl Stream for EventStream {
+    type Item = Result<Event, Lagged>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let missed = self.lagged.swap(0, Ordering::Relaxed);
+        if missed > 0 {
+            return Poll::Ready(Some(Err(Lagged(missed))));
+        }
+        self.receiver.poll_next_unpin(cx).map(|event| event.map(Ok))
+    }
+}
+
+impl FusedStream for EventStream {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
 }
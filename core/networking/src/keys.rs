@@ -0,0 +1,216 @@
+//! Node identities: keypairs of the supported types and their protobuf encoding, optionally
+//! encrypted with a passphrase, as used by key files and by [`export`] / [`import`].
+
+use argon2::Argon2;
+use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
+use libp2p::identity::{self, Keypair};
+use rand::{rngs::OsRng, RngCore};
+use crate::error::NetworkingError;
+
+#[cfg(not(target_family="wasm"))]
+use std::{fs, io::{self, Write}, path::Path};
+
+// An encrypted key starts with this header, followed by the argon2 salt, the nonce and the
+// ChaCha20-Poly1305 ciphertext of the protobuf encoded keypair.
+const ENCRYPTED_HEADER: &[u8] = b"posemesh-key-enc1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Type of the keys generated for new identities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    /// ECDSA on the P-256 curve.
+    Ecdsa,
+}
+
+pub fn generate(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        KeyType::Ecdsa => Keypair::generate_ecdsa(),
+    }
+}
+
+fn key_error(e: impl std::fmt::Display) -> NetworkingError {
+    NetworkingError::Key(e.to_string())
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, NetworkingError> {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key).map_err(key_error)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENCRYPTED_HEADER)
+}
+
+/// Encodes the keypair with protobuf, the format every libp2p implementation reads, and encrypts
+/// it when a passphrase is given.
+pub fn export(keypair: &Keypair, passphrase: Option<&str>) -> Result<Vec<u8>, NetworkingError> {
+    let encoded = keypair.to_protobuf_encoding().map_err(key_error)?;
+    let Some(passphrase) = passphrase else {
+        return Ok(encoded);
+    };
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(Nonce::from_slice(&nonce), encoded.as_slice())
+        .map_err(key_error)?;
+
+    let mut bytes = Vec::with_capacity(ENCRYPTED_HEADER.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    bytes.extend_from_slice(ENCRYPTED_HEADER);
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+    bytes.extend_from_slice(&ciphertext);
+    Ok(bytes)
+}
+
+/// Reads a keypair written by [`export`]. The passphrase is only needed for encrypted keys.
+pub fn import(bytes: &[u8], passphrase: Option<&str>) -> Result<Keypair, NetworkingError> {
+    if !is_encrypted(bytes) {
+        return Keypair::from_protobuf_encoding(bytes).map_err(key_error);
+    }
+    let Some(passphrase) = passphrase else {
+        return Err(NetworkingError::Key("the key is encrypted, a passphrase is required".to_string()));
+    };
+
+    let bytes = &bytes[ENCRYPTED_HEADER.len()..];
+    if bytes.len() < SALT_LEN + NONCE_LEN {
+        return Err(NetworkingError::Key("the encrypted key is truncated".to_string()));
+    }
+    let (salt, bytes) = bytes.split_at(SALT_LEN);
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let encoded = cipher(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| NetworkingError::Key("wrong passphrase or corrupt key".to_string()))?;
+    Keypair::from_protobuf_encoding(&encoded).map_err(key_error)
+}
+
+/// Reads a keypair given as config: an exported key, or the raw secret key bytes of `key_type`.
+pub fn from_bytes(bytes: &[u8], key_type: KeyType, passphrase: Option<&str>) -> Result<Keypair, NetworkingError> {
+    if is_encrypted(bytes) {
+        return import(bytes, passphrase);
+    }
+    if let Ok(keypair) = Keypair::from_protobuf_encoding(bytes) {
+        return Ok(keypair);
+    }
+    match key_type {
+        KeyType::Ed25519 => Keypair::ed25519_from_bytes(bytes.to_vec()).map_err(key_error),
+        KeyType::Secp256k1 => identity::secp256k1::SecretKey::try_from_bytes(bytes.to_vec())
+            .map(|secret| identity::secp256k1::Keypair::from(secret).into())
+            .map_err(key_error),
+        KeyType::Ecdsa => identity::ecdsa::SecretKey::try_from_bytes(bytes)
+            .map(|secret| identity::ecdsa::Keypair::from(secret).into())
+            .map_err(key_error),
+    }
+}
+
+/// Reads the key file at `path`, or creates it with a new key of `key_type` when it doesn't
+/// exist. A file that exists but can't be read is never replaced, that would change the
+/// identity of the node.
+#[cfg(not(target_family="wasm"))]
+pub(crate) fn load_or_create(path: &Path, key_type: KeyType, passphrase: Option<&str>) -> Result<Keypair, NetworkingError> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let keypair = import(&bytes, passphrase)
+                .map_err(|e| NetworkingError::Key(format!("can't read key file {}: {e}", path.display())))?;
+            if passphrase.is_some() && !is_encrypted(&bytes) {
+                tracing::warn!("Key file {} is not encrypted, the passphrase is ignored", path.display());
+            }
+            Ok(keypair)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = generate(key_type);
+            save(path, &keypair, passphrase)?;
+            tracing::info!("Created key file {}", path.display());
+            Ok(keypair)
+        }
+        Err(e) => Err(NetworkingError::Key(format!("can't read key file {}: {e}", path.display()))),
+    }
+}
+
+/// Writes the keypair to a new file at `path`, readable by the owner only. Fails if the file exists.
+#[cfg(not(target_family="wasm"))]
+pub fn save(path: &Path, keypair: &Keypair, passphrase: Option<&str>) -> Result<(), NetworkingError> {
+    let bytes = export(keypair, passphrase)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(&bytes)?;
+    Ok(file.sync_all()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_TYPES: [KeyType; 3] = [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa];
+
+    #[test]
+    fn export_import_round_trip() {
+        for key_type in KEY_TYPES {
+            let keypair = generate(key_type);
+            for passphrase in [None, Some("correct horse")] {
+                let bytes = export(&keypair, passphrase).unwrap();
+                assert_eq!(is_encrypted(&bytes), passphrase.is_some());
+                let imported = import(&bytes, passphrase).unwrap();
+                assert_eq!(imported.public(), keypair.public(), "{key_type:?} {passphrase:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn import_with_wrong_passphrase_fails() {
+        let bytes = export(&generate(KeyType::Ed25519), Some("correct horse")).unwrap();
+        assert!(matches!(import(&bytes, Some("battery staple")), Err(NetworkingError::Key(_))));
+        assert!(matches!(import(&bytes, None), Err(NetworkingError::Key(_))));
+    }
+
+    #[test]
+    fn import_truncated_key_fails() {
+        let bytes = export(&generate(KeyType::Ed25519), Some("correct horse")).unwrap();
+        for len in [ENCRYPTED_HEADER.len(), ENCRYPTED_HEADER.len() + SALT_LEN + NONCE_LEN, bytes.len() - 1] {
+            assert!(matches!(import(&bytes[..len], Some("correct horse")), Err(NetworkingError::Key(_))), "{len}");
+        }
+    }
+
+    #[test]
+    fn from_bytes_accepts_raw_secret_keys() {
+        let ed25519 = generate(KeyType::Ed25519).try_into_ed25519().unwrap();
+        let keypair = from_bytes(ed25519.secret().as_ref(), KeyType::Ed25519, None).unwrap();
+        assert_eq!(keypair.public(), identity::PublicKey::from(ed25519.public()));
+
+        let secp256k1 = generate(KeyType::Secp256k1).try_into_secp256k1().unwrap();
+        let keypair = from_bytes(&secp256k1.secret().to_bytes(), KeyType::Secp256k1, None).unwrap();
+        assert_eq!(keypair.public(), identity::PublicKey::from(secp256k1.public().clone()));
+
+        let ecdsa = generate(KeyType::Ecdsa).try_into_ecdsa().unwrap();
+        let keypair = from_bytes(&ecdsa.secret().to_bytes(), KeyType::Ecdsa, None).unwrap();
+        assert_eq!(keypair.public(), identity::PublicKey::from(ecdsa.public().clone()));
+    }
+
+    #[test]
+    fn load_or_create_keeps_corrupt_key_file() {
+        let dir = std::env::temp_dir().join(format!("posemesh-keys-{}", std::process::id()));
+        let path = dir.join("pkey");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, b"not a key").unwrap();
+
+        assert!(matches!(load_or_create(&path, KeyType::Ed25519, None), Err(NetworkingError::Key(_))));
+        assert_eq!(fs::read(&path).unwrap(), b"not a key");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,15 @@
 pub mod client;
+pub mod error;
 pub mod event;
+pub mod keys;
 pub mod libp2p;
+pub mod record;
+pub mod rpc;
+
+mod metrics;
+
+#[cfg(not(target_family="wasm"))]
+mod peer_store;
 
 #[cfg(feature="c")]
 mod binding_helper;
@@ -1,19 +1,21 @@
-use futures::{channel::{mpsc::{self, channel, Receiver}, oneshot}, lock::Mutex, AsyncWriteExt, SinkExt, StreamExt};
-use libp2p::{core::{muxing::StreamMuxerBox, upgrade::Version}, dcutr, yamux, noise, gossipsub::{self, IdentTopic}, kad::{self, store::MemoryStore, GetClosestPeersOk, ProgressStep, QueryId}, multiaddr::{Multiaddr, Protocol}, swarm::{behaviour::toggle::Toggle, DialError, NetworkBehaviour, SwarmEvent}, PeerId, Stream, StreamProtocol, Swarm, Transport};
+use futures::{channel::{mpsc::{self, channel}, oneshot}, future::BoxFuture, lock::Mutex, AsyncWriteExt, FutureExt, StreamExt};
+use libp2p::{allow_block_list, connection_limits, metrics::{Metrics, Recorder, Registry}, core::{muxing::StreamMuxerBox, upgrade::Version}, dcutr, yamux, noise, gossipsub::{self, IdentTopic, TopicHash}, kad::{self, store::{MemoryStore, RecordStore}, GetClosestPeersOk, ProgressStep, QueryId, RecordKey}, multiaddr::{Multiaddr, Protocol}, swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, ConnectionId, ListenerId, NetworkBehaviour, SwarmEvent}, PeerId, Stream, StreamProtocol, Swarm, Transport};
 use utils::retry_with_delay;
-use std::{collections::HashMap, error::Error, fmt::{self, Debug, Formatter}, io::{self, Read, Write}, str::FromStr, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::{self, Debug, Formatter}, net::SocketAddr, num::NonZeroU32, str::FromStr, sync::Arc, time::Duration};
 use rand::{thread_rng, rngs::OsRng};
 use serde::{Deserialize, Serialize};
 use libp2p_stream::{self as stream, IncomingStreams};
-use crate::{client::{self, Client}, event};
+use crate::{client::{self, Client}, error::NetworkingError, event, keys, metrics, record};
+#[cfg(not(target_family="wasm"))]
+use crate::peer_store;
 use std::net::{Ipv4Addr, IpAddr};
 
 #[cfg(not(target_family="wasm"))]
 use libp2p_webrtc as webrtc;
 #[cfg(not(target_family="wasm"))]
-use libp2p::{tcp, mdns};
+use libp2p::{core::transport::{Boxed, MemoryTransport}, tcp, mdns, pnet};
 #[cfg(not(target_family="wasm"))]
-use std::{fs, path::Path};
+use std::path::Path;
 
 #[cfg(not(target_family="wasm"))]
 use tokio::spawn;
@@ -22,8 +24,9 @@ use wasm_bindgen_futures::spawn_local as spawn;
 
 #[cfg(not(target_family="wasm"))]
 use tokio::time::sleep;
+#[cfg(target_family="wasm")]
+use utils::sleep;
 
-use futures::executor::block_on;
 
 #[cfg(target_family="wasm")]
 use libp2p_webrtc_websys as webrtc_websys;
@@ -73,47 +76,295 @@ struct PosemeshBehaviour {
     gossipsub: gossipsub::Behaviour,
     streams: stream::Behaviour,
     identify: libp2p::identify::Behaviour,
+    ping: libp2p::ping::Behaviour,
     kdht: Toggle<libp2p::kad::Behaviour<MemoryStore>>,
+    autonat: Toggle<libp2p::autonat::v1::Behaviour>,
+    // probes the relays that only serve AutoNAT v2
+    #[cfg(not(target_family="wasm"))]
     autonat_client: Toggle<libp2p::autonat::v2::client::Behaviour>,
     relay_client: Toggle<libp2p::relay::client::Behaviour>,
     #[cfg(not(target_family="wasm"))]
     mdns: Toggle<mdns::tokio::Behaviour>,
     #[cfg(not(target_family="wasm"))]
     relay: Toggle<libp2p::relay::Behaviour>,
+    // serves the nodes that still probe with AutoNAT v2
     #[cfg(not(target_family="wasm"))]
     autonat_server: Toggle<libp2p::autonat::v2::server::Behaviour>,
     dcutr: Toggle<libp2p::dcutr::Behaviour>,
+    allowed_peers: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    connection_limits: connection_limits::Behaviour,
+}
+
+/// How gossipsub derives message ids, which it uses to drop duplicates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageIdStrategy {
+    /// Message data and sequence number. Identical payloads published twice are both delivered.
+    DataAndSequenceNumber,
+    /// Publisher and sequence number, the gossipsub default. Cheaper for large messages.
+    SourceAndSequenceNumber,
+}
+
+#[derive(Clone, Debug)]
+pub struct GossipsubConfig {
+    pub heartbeat_interval: Duration,
+    /// Target number of peers in a topic mesh.
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    /// Minimum number of outbound peers in a topic mesh, must be below mesh_n_low.
+    pub mesh_outbound_min: usize,
+    /// Maximum size of a message, in bytes.
+    pub max_transmit_size: usize,
+    pub message_id_strategy: MessageIdStrategy,
+    /// Enables peer scoring. Peers below the thresholds are excluded from gossip, publishing or graylisted.
+    pub peer_score_thresholds: Option<gossipsub::PeerScoreThresholds>,
+    /// Only used when peer_score_thresholds is set.
+    pub peer_score_params: gossipsub::PeerScoreParams,
+    /// Adds every connected or discovered peer as an explicit peer. Explicit peers bypass the mesh
+    /// and receive every message, which is simple for small clusters but floods large ones.
+    pub add_explicit_peers: bool,
+}
+
+impl Default for GossipsubConfig {
+    fn default() -> Self {
+        GossipsubConfig {
+            heartbeat_interval: Duration::from_secs(10),
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            mesh_outbound_min: 2,
+            max_transmit_size: 65536,
+            message_id_strategy: MessageIdStrategy::DataAndSequenceNumber,
+            peer_score_thresholds: None,
+            peer_score_params: gossipsub::PeerScoreParams::default(),
+            add_explicit_peers: true,
+        }
+    }
+}
+
+/// Limits of the relay server, used when enable_relay_server is set. Rate limits are `(limit,
+/// interval)`: at most `limit` requests, refilled one per `interval`. None disables a rate limit,
+/// a rate limit with a zero interval is ignored.
+#[derive(Clone, Debug)]
+pub struct RelayServerConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration: Duration,
+    pub reservation_rate_per_peer: Option<(NonZeroU32, Duration)>,
+    pub reservation_rate_per_ip: Option<(NonZeroU32, Duration)>,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration: Duration,
+    /// Bytes relayed in each direction before a circuit is closed.
+    pub max_circuit_bytes: u64,
+    pub circuit_rate_per_peer: Option<(NonZeroU32, Duration)>,
+    pub circuit_rate_per_ip: Option<(NonZeroU32, Duration)>,
+    /// When not empty, only these peers may reserve a slot, everyone else is denied.
+    pub allowed_peers: Vec<String>,
+}
+
+impl Default for RelayServerConfig {
+    // libp2p's defaults, except for the larger circuits that data transfers need
+    fn default() -> Self {
+        RelayServerConfig {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(60 * 60),
+            reservation_rate_per_peer: NonZeroU32::new(30).map(|limit| (limit, Duration::from_secs(2 * 60))),
+            reservation_rate_per_ip: NonZeroU32::new(60).map(|limit| (limit, Duration::from_secs(60))),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1024 * 1024 * 1024, // 1GB
+            circuit_rate_per_peer: NonZeroU32::new(30).map(|limit| (limit, Duration::from_secs(2 * 60))),
+            circuit_rate_per_ip: NonZeroU32::new(60).map(|limit| (limit, Duration::from_secs(60))),
+            allowed_peers: vec![],
+        }
+    }
+}
+
+// libp2p-relay panics on a rate limit with a zero interval
+#[cfg(not(target_family="wasm"))]
+fn rate_limit(name: &str, rate: Option<(NonZeroU32, Duration)>) -> Option<(NonZeroU32, Duration)> {
+    match rate {
+        Some((_, interval)) if interval.is_zero() => {
+            tracing::error!("Ignoring relay server {name}: the interval must not be zero");
+            None
+        }
+        rate => rate,
+    }
+}
+
+#[cfg(not(target_family="wasm"))]
+fn relay_config(cfg: &RelayServerConfig) -> libp2p::relay::Config {
+    let mut relay_config = libp2p::relay::Config {
+        max_reservations: cfg.max_reservations,
+        max_reservations_per_peer: cfg.max_reservations_per_peer,
+        reservation_duration: cfg.reservation_duration,
+        reservation_rate_limiters: vec![],
+        max_circuits: cfg.max_circuits,
+        max_circuits_per_peer: cfg.max_circuits_per_peer,
+        max_circuit_duration: cfg.max_circuit_duration,
+        max_circuit_bytes: cfg.max_circuit_bytes,
+        circuit_src_rate_limiters: vec![],
+    };
+    if let Some((limit, interval)) = rate_limit("reservation_rate_per_peer", cfg.reservation_rate_per_peer) {
+        relay_config = relay_config.reservation_rate_per_peer(limit, interval);
+    }
+    if let Some((limit, interval)) = rate_limit("reservation_rate_per_ip", cfg.reservation_rate_per_ip) {
+        relay_config = relay_config.reservation_rate_per_ip(limit, interval);
+    }
+    if let Some((limit, interval)) = rate_limit("circuit_rate_per_peer", cfg.circuit_rate_per_peer) {
+        relay_config = relay_config.circuit_src_per_peer(limit, interval);
+    }
+    if let Some((limit, interval)) = rate_limit("circuit_rate_per_ip", cfg.circuit_rate_per_ip) {
+        relay_config = relay_config.circuit_src_per_ip(limit, interval);
+    }
+    if !cfg.allowed_peers.is_empty() {
+        // a limiter that never refills for strangers works as an allowlist
+        let allowed_peers = parse_peer_ids(&cfg.allowed_peers).into_iter().collect::<HashSet<_>>();
+        relay_config.reservation_rate_limiters.push(Box::new(move |peer_id: PeerId, _: &Multiaddr, _: web_time::Instant| allowed_peers.contains(&peer_id)));
+    }
+    relay_config
+}
+
+/// Transports used to dial and listen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportMode {
+    /// TCP and QUIC, plus WebSocket and WebRTC when enabled.
+    #[default]
+    Network,
+    /// libp2p's in-process MemoryTransport with noise and yamux. The node listens on `/memory/<port>`
+    /// and only reaches nodes of the same process, so whole clusters can run in a single test
+    /// without touching the OS network stack. mDNS, WebSocket and WebRTC are disabled. Not
+    /// supported on wasm.
+    Memory,
+}
+
+/// How the node probes its reachability with AutoNAT. Connected peers, bootstrap and relay nodes
+/// are asked to dial back the node's addresses. Relays that only serve AutoNAT v2 are probed with
+/// v2 until a v1 server answers. Browsers can't be dialed back and are always private.
+#[derive(Clone, Debug)]
+pub struct NatConfig {
+    /// Delay between probes while the status is unknown or not fully confirmed.
+    pub retry_interval: Duration,
+    /// Delay between probes once confidence_max is reached.
+    pub refresh_interval: Duration,
+    /// Probes that must contradict a status before it flips, and the highest confidence.
+    pub confidence_max: usize,
+    /// Confidence a private status needs before the node listens on its relays, and a public one
+    /// before the relay listeners are dropped. 0 reacts to the first probe.
+    pub relay_confidence: usize,
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        NatConfig {
+            retry_interval: Duration::from_secs(90),
+            refresh_interval: Duration::from_secs(15 * 60),
+            confidence_max: 3,
+            relay_confidence: 1,
+        }
+    }
+}
+
+/// Restricts who may connect and how many connections are kept. Limits are unbounded when None.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionGatingConfig {
+    /// When not empty, only these peers may connect or be dialed, bootstrap and relay nodes included.
+    pub allowed_peers: Vec<String>,
+    pub denied_peers: Vec<String>,
+    pub max_connections: Option<u32>,
+    pub max_connections_per_peer: Option<u32>,
+    pub max_pending_incoming: Option<u32>,
 }
 
 #[derive(Clone)]
 pub struct NetworkingConfig {
     pub enable_relay_server: bool,
+    pub relay_server: RelayServerConfig,
+    /// TCP and QUIC listen on this port on every IPv4 interface, WebSocket too when enabled and
+    /// WebRTC on the next one. Ignored when listen_addrs is set.
     pub port: u16,
+    /// Addresses to listen on, e.g. `/ip6/::/tcp/4001`, `/ip4/127.0.0.1/udp/4001/quic-v1` or
+    /// `/ip4/0.0.0.0/tcp/4002/ws`. Replaces the listeners derived from port, enable_websocket
+    /// and enable_webrtc when not empty.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses other nodes can reach this node on that it can't find out by itself, like a
+    /// port forwarded by the router. They are announced without waiting for AutoNAT.
+    pub external_addrs: Vec<Multiaddr>,
     pub bootstrap_nodes: Vec<String>,
     pub relay_nodes: Vec<String>,
     pub enable_mdns: bool,
+    /// Secret key of the node: a key written by [`keys::export`], or the raw secret key bytes of
+    /// key_type.
     pub private_key: Option<Vec<u8>>,
+    /// Key file used when private_key is None. It is created with a new key of key_type when
+    /// missing; a file that can't be read fails the start instead of being replaced.
     pub private_key_path: Option<String>,
+    /// Encrypts a new key file, and decrypts an encrypted key file or private_key.
+    pub private_key_passphrase: Option<String>,
+    /// Type of a newly generated key, and of a raw private_key.
+    pub key_type: keys::KeyType,
     pub enable_kdht: bool,
     pub name: String,
     pub enable_websocket: bool,
     pub enable_webrtc: bool,
+    pub gossipsub: GossipsubConfig,
+    pub connection_gating: ConnectionGatingConfig,
+    pub nat: NatConfig,
+    /// Joins the private network protected by this key: connections are only established with
+    /// nodes holding the same key. TCP and WebSocket are wrapped with libp2p pnet; QUIC and WebRTC
+    /// bring their own encryption that pnet can't wrap, so they are disabled. Not supported on wasm.
+    pub pre_shared_key: Option<[u8; 32]>,
+    /// How often the DHT routing table is refreshed with a bootstrap query.
+    pub dht_bootstrap_interval: Duration,
+    /// Delay before re-dialing a lost bootstrap or relay node. It doubles after every failed
+    /// attempt, up to max_redial_backoff.
+    pub redial_backoff: Duration,
+    pub max_redial_backoff: Duration,
+    /// Resources announced to the other nodes, see [`NodeResources`]. Nothing is announced when None.
+    pub resources: Option<NodeResources>,
+    /// How often the resources are announced again, so that new nodes learn about them and
+    /// stale entries get refreshed.
+    pub announce_interval: Duration,
+    /// Serves the metrics returned by [`Networking::metrics`] over HTTP on this address, for
+    /// Prometheus to scrape. Ignored in the browser.
+    pub metrics_address: Option<SocketAddr>,
+    pub transport: TransportMode,
 }
 
 impl Default for NetworkingConfig {
     fn default() -> Self {
         NetworkingConfig{
             port: 0,
+            listen_addrs: vec![],
+            external_addrs: vec![],
             bootstrap_nodes: vec![],
             enable_relay_server: false,
+            relay_server: RelayServerConfig::default(),
             enable_kdht: false,
             enable_mdns: true,
             relay_nodes: vec![],
             private_key: None,
             private_key_path: Some("/volume/pkey".to_string()),
+            private_key_passphrase: None,
+            key_type: keys::KeyType::Ed25519,
             name: "Placeholder".to_string(),
             enable_webrtc: false,
             enable_websocket: false, // placeholder
+            gossipsub: GossipsubConfig::default(),
+            connection_gating: ConnectionGatingConfig::default(),
+            nat: NatConfig::default(),
+            pre_shared_key: None,
+            dht_bootstrap_interval: Duration::from_secs(5 * 60),
+            redial_backoff: Duration::from_secs(2),
+            max_redial_backoff: Duration::from_secs(5 * 60),
+            resources: None,
+            announce_interval: Duration::from_secs(30),
+            metrics_address: None,
+            transport: TransportMode::Network,
         }
     }
 }
@@ -122,75 +373,271 @@ impl Default for NetworkingConfig {
 pub struct Node {
     pub id: String,
     pub name: String,
-    pub capabilities: Vec<String>
+    pub capabilities: Vec<String>,
+    /// None until the node announced its resources.
+    #[serde(default)]
+    pub resources: Option<NodeResources>,
+}
+
+/// What the node knows about a connected peer.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Remote address of every open connection to the peer.
+    pub addresses: Vec<Multiaddr>,
+    /// Protocols the peer supports, empty until it sent its identify info.
+    pub protocols: Vec<String>,
+    pub agent_version: Option<String>,
+    /// Average round-trip time of the last pings, None until the first one is answered.
+    pub rtt: Option<Duration>,
+}
+
+/// Hardware a node offers to run tasks, announced on the capabilities topic.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct NodeResources {
+    pub cpu_cores: u32,
+    pub gpu_count: u32,
+    /// Memory of all GPUs together, in bytes.
+    pub gpu_memory: u64,
+    /// In bytes.
+    pub ram: u64,
+    /// In bytes.
+    pub free_disk: u64,
+    /// Share of the node's capacity in use, from 0.0 to 1.0.
+    pub load: f32,
+}
+
+#[derive(Default)]
+struct ConnectedPeer {
+    connections: HashMap<ConnectionId, Multiaddr>,
+    protocols: Vec<String>,
+    agent_version: Option<String>,
+    // latest ping round-trip times
+    rtts: VecDeque<Duration>,
+}
+
+impl ConnectedPeer {
+    fn info(&self, peer_id: PeerId) -> PeerInfo {
+        let rtt = (!self.rtts.is_empty()).then(|| self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32);
+        PeerInfo {
+            peer_id,
+            addresses: self.connections.values().cloned().collect(),
+            protocols: self.protocols.clone(),
+            agent_version: self.agent_version.clone(),
+            rtt,
+        }
+    }
+}
+
+// A get_record query waiting for enough peers to agree on a value.
+struct GetRecordRequest {
+    key: String,
+    quorum: kad::Quorum,
+    // verified values and their publishers, one entry per peer that returned the record
+    found: Vec<(Vec<u8>, PeerId)>,
+    sender: oneshot::Sender<Result<record::DhtRecord, NetworkingError>>,
+}
+
+impl GetRecordRequest {
+    // Number of peers that must return the same record. Majority and All are relative to the
+    // peers that returned the record, so they are only known once the query finished.
+    fn required(&self, finished: bool) -> Option<usize> {
+        match self.quorum {
+            kad::Quorum::One => Some(1),
+            kad::Quorum::N(n) => Some(n.get()),
+            kad::Quorum::Majority if finished => Some(self.found.len() / 2 + 1),
+            kad::Quorum::All if finished => Some(self.found.len().max(1)),
+            _ => None,
+        }
+    }
+
+    fn agreed(&self, finished: bool) -> Option<record::DhtRecord> {
+        let required = self.required(finished)?;
+        self.found.iter()
+            .find(|found| self.found.iter().filter(|other| other == found).count() >= required)
+            .map(|(value, publisher)| record::DhtRecord { key: self.key.clone(), value: value.clone(), publisher: *publisher })
+    }
+}
+
+// A gossipsub message whose validator has resolved.
+struct ValidatedMessage {
+    message_id: gossipsub::MessageId,
+    propagation_source: PeerId,
+    message: event::PubsubMessage,
+    validation: event::Validation,
 }
 
 const POSEMESH_PROTO_NAME: StreamProtocol = StreamProtocol::new("/posemesh/kad/1.0.0");
+// nodes publish their Node, resources included, on this topic
+const CAPABILITIES_TOPIC: &str = "/posemesh/capabilities/1.0.0";
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const REDIAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// a listener that reported no address by then, like a relay listener without reservation, fails the start
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+// number of pings the RTT of a peer is averaged over
+const RTT_WINDOW: usize = 10;
+
+#[cfg(not(target_family="wasm"))]
+type Ticker = futures::stream::Fuse<futures::stream::BoxStream<'static, ()>>;
+#[cfg(target_family="wasm")]
+type Ticker = futures::stream::Fuse<futures::stream::LocalBoxStream<'static, ()>>;
+
+fn kdht_disabled() -> NetworkingError {
+    NetworkingError::Dht("Kademlia is disabled".to_string())
+}
+
+fn ticker(period: Duration) -> Ticker {
+    #[cfg(not(target_family="wasm"))]
+    return futures::stream::unfold((), move |_| async move {
+        sleep(period).await;
+        Some(((), ()))
+    }).boxed().fuse();
+    #[cfg(target_family="wasm")]
+    return gloo_timers::future::IntervalStream::new(period.as_millis() as u32).boxed_local().fuse();
+}
 
 struct Libp2p {
     // nodes_map: HashMap<String, Node>,
     swarm: Swarm<PosemeshBehaviour>,
     cfg: NetworkingConfig,
+    // signs the records published to the DHT
+    keypair: libp2p::identity::Keypair,
     command_receiver: mpsc::Receiver<client::Command>,
     pub node: Node,
     // node_regsiter_topic: IdentTopic,
-    event_sender: mpsc::Sender<event::Event>,
-    find_peer_requests: Arc<Mutex<HashMap<QueryId, oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>>>>,
+    event_bus: event::EventBus,
+    metrics: Metrics,
+    counters: metrics::Counters,
+    find_peer_requests: Arc<Mutex<HashMap<QueryId, oneshot::Sender<Result<(), NetworkingError>>>>>,
+    provide_requests: HashMap<QueryId, oneshot::Sender<Result<(), NetworkingError>>>,
+    // providers found so far by each query
+    find_providers_requests: HashMap<QueryId, (HashSet<PeerId>, oneshot::Sender<Result<Vec<PeerId>, NetworkingError>>)>,
+    put_record_requests: HashMap<QueryId, oneshot::Sender<Result<(), NetworkingError>>>,
+    get_record_requests: HashMap<QueryId, GetRecordRequest>,
+    listeners: HashMap<ListenerId, Multiaddr>,
+    shutdown_sender: Option<oneshot::Sender<()>>,
+    // stopped on shutdown, so that the port is free for the next node
+    #[cfg(not(target_family="wasm"))]
+    metrics_server: Option<tokio::task::JoinHandle<()>>,
+    nat_status: event::Reachability,
+    // nat_status comes from AutoNAT v2 probes, because no server answered AutoNAT v1 yet
+    nat_status_from_v2: bool,
+    topic_subscribers: HashMap<TopicHash, HashMap<u64, mpsc::Sender<event::PubsubMessage>>>,
+    next_subscription_id: u64,
+    validators: HashMap<TopicHash, event::TopicValidator>,
+    validation_sender: mpsc::Sender<ValidatedMessage>,
+    validation_receiver: mpsc::Receiver<ValidatedMessage>,
+    maintenance: Ticker,
+    dht_bootstrap: Ticker,
+    redial: Ticker,
+    announce: Ticker,
+    relays: Vec<(PeerId, Multiaddr)>,
+    relay_listeners: HashMap<PeerId, ListenerId>,
+    // bootstrap and relay nodes, kept connected for the lifetime of the node
+    persistent_peers: HashMap<PeerId, Vec<Multiaddr>>,
+    // when each lost persistent peer is dialed next, and the backoff that led to it
+    redials: HashMap<PeerId, (web_time::Instant, Duration)>,
+    connected_peers: HashMap<PeerId, ConnectedPeer>,
+    dial_requests: HashMap<ConnectionId, oneshot::Sender<Result<PeerId, NetworkingError>>>,
+    // peers whose addresses were learned out-of-band, they are dialed without a DHT lookup
+    peer_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    // unix timestamps of the last connection to each peer, persisted with the routing table
+    #[cfg(not(target_family="wasm"))]
+    peers_last_seen: HashMap<PeerId, u64>,
 }
 
-#[cfg(not(target_family="wasm"))]
-fn keypair_file(private_key_path: &String) -> libp2p::identity::Keypair {
-    let path = Path::new(private_key_path);
-    // Check if the keypair file exists
-    if let Ok(mut file) = fs::File::open(path) {
-        // Read the keypair from the file
-        let mut keypair_bytes = Vec::new();
-        if file.read_to_end(&mut keypair_bytes).is_ok() {
-            if let Ok(keypair) = libp2p::identity::Keypair::from_protobuf_encoding(&keypair_bytes) {
-                return keypair;
-            }
-        }
+fn parse_or_create_keypair(cfg: &NetworkingConfig) -> Result<libp2p::identity::Keypair, NetworkingError> {
+    let passphrase = cfg.private_key_passphrase.as_deref();
+    if let Some(private_key) = cfg.private_key.as_ref().filter(|key| !key.is_empty()) {
+        return keys::from_bytes(private_key, cfg.key_type, passphrase);
     }
 
-    // If the file does not exist or reading failed, create a new keypair
-    let keypair = libp2p::identity::Keypair::generate_ed25519();
-
-    if let Some(parent) = path.parent() {
-        if let Err(err) = fs::create_dir_all(parent) {
-            tracing::error!("Failed to create directory: {err}");
-        }
+    #[cfg(not(target_family="wasm"))]
+    if let Some(key_path) = cfg.private_key_path.as_ref() {
+        return keys::load_or_create(Path::new(key_path), cfg.key_type, passphrase);
     }
 
-    // Save the new keypair to the file
-    if let Ok(mut file) = fs::File::create(path) {
-        let keypair_bytes = keypair.to_protobuf_encoding().expect("Failed to encode keypair");
-        if file.write_all(&keypair_bytes).is_err() {
-            tracing::error!("Failed to write keypair to file");
-        }
-    }
+    Ok(keys::generate(cfg.key_type))
+}
 
-    keypair
+#[cfg(not(target_family="wasm"))]
+fn private_transport(key: &libp2p::identity::Keypair, psk: pnet::PreSharedKey) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn std::error::Error + Send + Sync>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+    let ws = libp2p::websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)));
+
+    Ok(ws.or_transport(tcp)
+        .and_then(move |socket, _| pnet::PnetConfig::new(psk).handshake(socket))
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed())
 }
 
-fn parse_or_create_keypair(
-    private_key: Option<Vec<u8>>,
-    private_key_path: Option<String>,
-) -> libp2p::identity::Keypair {
-    let private_key = private_key.unwrap_or_default();
-    // load private key into keypair
-    if let Ok(keypair) = libp2p::identity::Keypair::ed25519_from_bytes(private_key) {
-        return keypair;
-    }
+#[cfg(not(target_family="wasm"))]
+fn memory_transport(key: &libp2p::identity::Keypair, psk: Option<pnet::PreSharedKey>) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(MemoryTransport::default()
+        .and_then(move |socket, _| async move {
+            match psk {
+                Some(psk) => pnet::PnetConfig::new(psk).handshake(socket).await.map(futures::future::Either::Left),
+                None => Ok(futures::future::Either::Right(socket)),
+            }
+        })
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed())
+}
 
+async fn build_swarm(key: libp2p::identity::Keypair, mut behavior: PosemeshBehaviour, pre_shared_key: Option<[u8; 32]>, transport: TransportMode, registry: &mut Registry) -> Result<Swarm<PosemeshBehaviour>, NetworkingError> {
     #[cfg(not(target_family="wasm"))]
-    if let Some(key_path) = private_key_path {
-        return keypair_file(&key_path);
+    if transport == TransportMode::Memory {
+        let psk = pre_shared_key.map(pnet::PreSharedKey::new);
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(key)
+            .with_tokio()
+            .with_other_transport(|id_keys| memory_transport(id_keys, psk))
+            .map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_relay_client(noise::Config::new, yamux::Config::default).map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_bandwidth_metrics(registry)
+            .with_behaviour(|_, relay_behavior| {
+                behavior.relay_client = Some(relay_behavior).into();
+                behavior
+            }).map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        return Ok(swarm);
+    }
+    #[cfg(target_family="wasm")]
+    if transport == TransportMode::Memory {
+        return Err(NetworkingError::Transport("the memory transport is not supported in the browser".to_string()));
     }
 
-    libp2p::identity::Keypair::generate_ed25519()
-}
+    #[cfg(not(target_family="wasm"))]
+    if let Some(psk) = pre_shared_key {
+        let psk = pnet::PreSharedKey::new(psk);
+        tracing::info!("Joining private network {}", psk.fingerprint());
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(key)
+            .with_tokio()
+            .with_other_transport(|id_keys| private_transport(id_keys, psk))
+            .map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_dns()?
+            .with_relay_client(noise::Config::new, yamux::Config::default).map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_bandwidth_metrics(registry)
+            .with_behaviour(|_, relay_behavior| {
+                behavior.relay_client = Some(relay_behavior).into();
+                behavior
+            }).map_err(|e| NetworkingError::Transport(e.to_string()))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        return Ok(swarm);
+    }
+    #[cfg(target_family="wasm")]
+    if pre_shared_key.is_some() {
+        return Err(NetworkingError::Transport("private networks are not supported in the browser".to_string()));
+    }
 
-async fn build_swarm(key: libp2p::identity::Keypair, mut behavior: PosemeshBehaviour) -> Result<Swarm<PosemeshBehaviour>, Box<dyn Error + Send + Sync>> {
     #[cfg(not(target_family="wasm"))]
     let swarm = libp2p::SwarmBuilder::with_existing_identity(key)
         .with_tokio()
@@ -198,7 +645,7 @@ async fn build_swarm(key: libp2p::identity::Keypair, mut behavior: PosemeshBehav
             tcp::Config::default().nodelay(true),
             noise::Config::new,
             yamux::Config::default,
-        )?
+        ).map_err(|e| NetworkingError::Transport(e.to_string()))?
         .with_quic()
         .with_other_transport(|id_keys| {
             Ok(webrtc::tokio::Transport::new(
@@ -206,17 +653,18 @@ async fn build_swarm(key: libp2p::identity::Keypair, mut behavior: PosemeshBehav
                 webrtc::tokio::Certificate::generate(&mut thread_rng())?,
             )
             .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
-        })?
+        }).map_err(|e| NetworkingError::Transport(e.to_string()))?
         .with_dns()?
         .with_websocket(
             noise::Config::new,
             yamux::Config::default,
-        ).await?
-        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        ).await.map_err(|e| NetworkingError::Transport(e.to_string()))?
+        .with_relay_client(noise::Config::new, yamux::Config::default).map_err(|e| NetworkingError::Transport(e.to_string()))?
+        .with_bandwidth_metrics(registry)
         .with_behaviour(|_, relay_behavior| {
             behavior.relay_client = Some(relay_behavior).into();
             behavior
-        })?
+        }).map_err(|e| NetworkingError::Transport(e.to_string()))?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
 
@@ -225,35 +673,80 @@ async fn build_swarm(key: libp2p::identity::Keypair, mut behavior: PosemeshBehav
         .with_wasm_bindgen()
         .with_other_transport(|key| {
             webrtc_websys::Transport::new(webrtc_websys::Config::new(&key))
-        })?
+        }).map_err(|e| NetworkingError::Transport(e.to_string()))?
         .with_other_transport(|key| {
             Ok(ws_websys::Transport::default()
             .upgrade(Version::V1Lazy)
             .authenticate(noise::Config::new(&key).expect("Failed to create noise config"))
             .multiplex(yamux::Config::default()))
-        })?
-        .with_behaviour(|_| behavior)?
+        }).map_err(|e| NetworkingError::Transport(e.to_string()))?
+        .with_bandwidth_metrics(registry)
+        .with_behaviour(|_| behavior).map_err(|e| NetworkingError::Transport(e.to_string()))?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
 
     Ok(swarm)
 }
 
-fn build_behavior(key: libp2p::identity::Keypair, cfg: &NetworkingConfig) -> PosemeshBehaviour {
-    let gossipsub_config = gossipsub::ConfigBuilder::default()
-        .heartbeat_interval(Duration::from_secs(10))
+fn parse_peer_ids(peer_ids: &[String]) -> Vec<PeerId> {
+    peer_ids.iter().filter_map(|peer_id| match PeerId::from_str(peer_id) {
+        Ok(peer_id) => Some(peer_id),
+        Err(e) => {
+            tracing::error!("Ignoring invalid peer id {peer_id}: {e}");
+            None
+        }
+    }).collect()
+}
+
+// Parses `/.../p2p/<peer id>` addresses, skipping the ones without a valid peer id.
+fn parse_peer_addresses(addresses: &[String]) -> Vec<(PeerId, Multiaddr)> {
+    addresses.iter().filter_map(|address| {
+        let maddr = match Multiaddr::from_str(address) {
+            Ok(maddr) => maddr,
+            Err(e) => {
+                tracing::error!("Ignoring invalid address {address}: {e}");
+                return None;
+            }
+        };
+        match maddr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Some((peer_id, maddr)),
+            _ => {
+                tracing::error!("Ignoring address without peer id: {address}");
+                None
+            }
+        }
+    }).collect()
+}
+
+fn build_behavior(key: libp2p::identity::Keypair, cfg: &NetworkingConfig) -> Result<PosemeshBehaviour, NetworkingError> {
+    let mut gossipsub_config = gossipsub::ConfigBuilder::default();
+    gossipsub_config
+        .heartbeat_interval(cfg.gossipsub.heartbeat_interval)
+        .mesh_n(cfg.gossipsub.mesh_n)
+        .mesh_n_low(cfg.gossipsub.mesh_n_low)
+        .mesh_n_high(cfg.gossipsub.mesh_n_high)
+        .mesh_outbound_min(cfg.gossipsub.mesh_outbound_min)
+        .max_transmit_size(cfg.gossipsub.max_transmit_size)
         .validation_mode(gossipsub::ValidationMode::Strict)
-        .message_id_fn(|message: &gossipsub::Message| {
+        // messages are reported back once the topic validator, if any, has run
+        .validate_messages();
+    if cfg.gossipsub.message_id_strategy == MessageIdStrategy::DataAndSequenceNumber {
+        gossipsub_config.message_id_fn(|message: &gossipsub::Message| {
             gossipsub::MessageId::from(format!("{}-{:?}", String::from_utf8_lossy(&message.data), message.sequence_number.unwrap()))
-        })
-        .build()
-        .expect("Failed to build gossipsub config");
+        });
+    }
+    let gossipsub_config = gossipsub_config.build()
+        .map_err(|e| NetworkingError::InvalidConfig(format!("gossipsub: {e}")))?;
 
-    let gossipsub = gossipsub::Behaviour::new(
+    let mut gossipsub = gossipsub::Behaviour::new(
         gossipsub::MessageAuthenticity::Signed(key.clone()),
         gossipsub_config,
     )
-    .expect("Failed to build gossipsub behaviour");
+    .map_err(|e| NetworkingError::InvalidConfig(format!("gossipsub: {e}")))?;
+    if let Some(thresholds) = cfg.gossipsub.peer_score_thresholds.clone() {
+        gossipsub.with_peer_score(cfg.gossipsub.peer_score_params.clone(), thresholds)
+            .map_err(|e| NetworkingError::InvalidConfig(format!("gossipsub peer scoring: {e}")))?;
+    }
 
     let streams = stream::Behaviour::new();
     let identify = libp2p::identify::Behaviour::new(
@@ -266,6 +759,9 @@ fn build_behavior(key: libp2p::identity::Keypair, cfg: &NetworkingConfig) -> Pos
         gossipsub,
         streams,
         identify,
+        ping: libp2p::ping::Behaviour::new(libp2p::ping::Config::default()),
+        autonat: None.into(),
+        #[cfg(not(target_family="wasm"))]
         autonat_client: None.into(),
         relay_client: None.into(),
         kdht: None.into(),
@@ -276,30 +772,72 @@ fn build_behavior(key: libp2p::identity::Keypair, cfg: &NetworkingConfig) -> Pos
         #[cfg(not(target_family="wasm"))]
         autonat_server: None.into(),
         dcutr: None.into(),
+        allowed_peers: None.into(),
+        blocked_peers: allow_block_list::Behaviour::default(),
+        connection_limits: connection_limits::Behaviour::new(
+            connection_limits::ConnectionLimits::default()
+                .with_max_established(cfg.connection_gating.max_connections)
+                .with_max_established_per_peer(cfg.connection_gating.max_connections_per_peer)
+                .with_max_pending_incoming(cfg.connection_gating.max_pending_incoming),
+        ),
     };
 
+    if !cfg.connection_gating.allowed_peers.is_empty() {
+        let mut allowed_peers = allow_block_list::Behaviour::<allow_block_list::AllowedPeers>::default();
+        for peer_id in parse_peer_ids(&cfg.connection_gating.allowed_peers) {
+            allowed_peers.allow_peer(peer_id);
+        }
+        behavior.allowed_peers = Some(allowed_peers).into();
+    }
+    for peer_id in parse_peer_ids(&cfg.connection_gating.denied_peers) {
+        behavior.blocked_peers.block_peer(peer_id);
+    }
+
     #[cfg(not(target_family="wasm"))]
-    if cfg.enable_mdns {
-        let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
-            .expect("Failed to build mdns behaviour");
+    if cfg.enable_mdns && cfg.transport == TransportMode::Network {
+        let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
         behavior.mdns = Some(mdns).into();
     }
     
     #[cfg(not(target_family="wasm"))]
     if cfg.enable_relay_server {
-        let mut relay_config = libp2p::relay::Config::default();
-        relay_config.max_circuit_bytes = 1024 * 1024 * 1024; // 1GB
-        let relay = libp2p::relay::Behaviour::new(key.public().to_peer_id(), relay_config);
+        let relay = libp2p::relay::Behaviour::new(key.public().to_peer_id(), relay_config(&cfg.relay_server));
         behavior.relay = Some(relay).into();
         behavior.autonat_server = Some(libp2p::autonat::v2::server::Behaviour::new(OsRng)).into();
     } else {
-        behavior.autonat_client = Some(libp2p::autonat::v2::client::Behaviour::new(OsRng,libp2p::autonat::v2::client::Config::default())).into();
+        #[cfg(not(target_family="wasm"))]
+        {
+            behavior.autonat_client = Some(libp2p::autonat::v2::client::Behaviour::new(OsRng, libp2p::autonat::v2::client::Config::default())).into();
+        }
         behavior.dcutr = Some(libp2p::dcutr::Behaviour::new(key.public().to_peer_id())).into();
     }
 
+    // Relay nodes probe as well, and answer the probes of the other nodes. Browsers can't be
+    // dialed back, they are always private.
+    #[cfg(not(target_family="wasm"))]
+    {
+        let mut autonat = libp2p::autonat::v1::Behaviour::new(key.public().to_peer_id(), libp2p::autonat::v1::Config {
+            boot_delay: Duration::from_secs(5),
+            retry_interval: cfg.nat.retry_interval,
+            refresh_interval: cfg.nat.refresh_interval,
+            confidence_max: cfg.nat.confidence_max,
+            // nodes of a LAN or of a single host probe each other too
+            only_global_ips: false,
+            ..Default::default()
+        });
+        for (peer_id, address) in parse_peer_addresses(&cfg.bootstrap_nodes).into_iter().chain(parse_peer_addresses(&cfg.relay_nodes)) {
+            autonat.add_server(peer_id, Some(address));
+        }
+        behavior.autonat = Some(autonat).into();
+    }
+
     if cfg.enable_kdht {
         let mut kad_cfg = libp2p::kad::Config::new(POSEMESH_PROTO_NAME);
         kad_cfg.set_query_timeout(Duration::from_secs(5));
+        // inbound records are stored by handle_event once their signature is checked
+        kad_cfg.set_record_filtering(kad::StoreInserts::FilterBoth);
+        // bootstrapping is driven by the networking loop, on dht_bootstrap_interval
+        kad_cfg.set_periodic_bootstrap_interval(None);
         let store = libp2p::kad::store::MemoryStore::new(key.public().to_peer_id());
         let mut kdht = libp2p::kad::Behaviour::with_config(key.public().to_peer_id(), store, kad_cfg);
 
@@ -312,29 +850,41 @@ fn build_behavior(key: libp2p::identity::Keypair, cfg: &NetworkingConfig) -> Pos
         let bootstrap_nodes = cfg.bootstrap_nodes.clone();
         for bootstrap in bootstrap_nodes {
             let peer_id = match bootstrap.split('/').last() {
-                Some(peer_id) => PeerId::from_str(peer_id).unwrap(),
+                Some(peer_id) => PeerId::from_str(peer_id)
+                    .map_err(|e| NetworkingError::InvalidConfig(format!("bootstrap node {bootstrap}: {e}")))?,
                 None => continue,
             };
-            let maddr = Multiaddr::from_str(&bootstrap).expect("Failed to parse bootstrap node address");
+            let maddr = Multiaddr::from_str(&bootstrap)
+                .map_err(|e| NetworkingError::InvalidConfig(format!("bootstrap node {bootstrap}: {e}")))?;
             let _ = kdht.add_address(&peer_id, maddr);
         }
 
         behavior.kdht = Some(kdht).into();
     }
 
-    behavior
+    Ok(behavior)
 }
 
-fn build_listeners(port: u16) -> Vec<Multiaddr> {
+fn build_listeners(port: u16, private: bool, transport: TransportMode) -> Vec<Multiaddr> {
+    if transport == TransportMode::Memory {
+        // port 0 lets the transport pick a free one
+        return vec![Multiaddr::empty().with(Protocol::Memory(port.into()))];
+    }
     #[cfg(not(target_family="wasm"))]
-    return vec![
-        Multiaddr::empty()
-            .with(Protocol::Ip4(Ipv4Addr::UNSPECIFIED))
-            .with(Protocol::Tcp(port)),
-        Multiaddr::from(Ipv4Addr::UNSPECIFIED)
-            .with(Protocol::Udp(port))
-            .with(Protocol::QuicV1),
-    ];
+    {
+        let mut listeners = vec![
+            Multiaddr::empty()
+                .with(Protocol::Ip4(Ipv4Addr::UNSPECIFIED))
+                .with(Protocol::Tcp(port)),
+        ];
+        // QUIC can't be wrapped with a pre-shared key
+        if !private {
+            listeners.push(Multiaddr::from(Ipv4Addr::UNSPECIFIED)
+                .with(Protocol::Udp(port))
+                .with(Protocol::QuicV1));
+        }
+        return listeners;
+    }
     #[cfg(target_family="wasm")]
     return vec![];
 }
@@ -356,67 +906,194 @@ fn enable_webrtc(port: u16) -> Multiaddr {
 }
 
 impl Libp2p {
-    pub async fn new(cfg: &NetworkingConfig, command_receiver: mpsc::Receiver<client::Command>, event_sender: mpsc::Sender<event::Event>) -> Result<Node, Box<dyn Error + Send + Sync>> {
-        let private_key = cfg.private_key.clone();
-        let key = parse_or_create_keypair(private_key, cfg.private_key_path.clone());
+    pub async fn new(cfg: &NetworkingConfig, command_receiver: mpsc::Receiver<client::Command>, event_bus: event::EventBus, registry: Arc<std::sync::Mutex<Registry>>) -> Result<Self, NetworkingError> {
+        let key = parse_or_create_keypair(cfg)?;
         println!("Your Peer Id: {:?}", key.public().to_peer_id());
 
-        let behaviour = build_behavior(key.clone(), cfg);
-
-        let mut swarm = build_swarm(key.clone(), behaviour).await?;
-
-        let mut listeners = build_listeners(cfg.port);
-        if cfg.enable_websocket {
-            listeners.push(enable_websocket(cfg.port));
-        }
-        if cfg.enable_webrtc {
-            listeners.push(enable_webrtc(cfg.port));
+        let behaviour = build_behavior(key.clone(), cfg)?;
+
+        let mut local_registry = Registry::default();
+        let mut swarm = build_swarm(key.clone(), behaviour, cfg.pre_shared_key, cfg.transport, &mut local_registry).await?;
+        let metrics = Metrics::new(&mut local_registry);
+        let counters = metrics::Counters::new(&mut local_registry);
+        // the metrics are shared handles, so the registry keeps reading their current values
+        *registry.lock().unwrap() = local_registry;
+
+        let private = cfg.pre_shared_key.is_some();
+        let mut listeners = cfg.listen_addrs.clone();
+        if listeners.is_empty() {
+            listeners = build_listeners(cfg.port, private, cfg.transport);
+            // the memory transport can't carry WebSocket or WebRTC
+            let network = cfg.transport == TransportMode::Network;
+            if cfg.enable_websocket && network {
+                listeners.push(enable_websocket(cfg.port));
+            }
+            if cfg.enable_webrtc && network && private {
+                tracing::warn!("WebRTC is disabled in private networks");
+            } else if cfg.enable_webrtc && network {
+                listeners.push(enable_webrtc(cfg.port));
+            }
         }
-        for addr in listeners.iter() {
+        let mut listener_ids = HashMap::with_capacity(listeners.len());
+        let mut listen_failures = Vec::new();
+        for addr in listeners {
             match swarm.listen_on(addr.clone()) {
-                Ok(_) => {},
-                Err(e) => {
-                    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos"))]
-                    eprintln!("Failed to initialize networking: Apple platforms require 'com.apple.security.network.server' entitlement set to YES.");
-                    return Err(Box::new(e));
+                Ok(id) => {
+                    listener_ids.insert(id, addr);
                 }
+                Err(e) => listen_failures.push((addr, e.to_string())),
             }
         }
+        if !listen_failures.is_empty() {
+            #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos"))]
+            eprintln!("Failed to initialize networking: Apple platforms require 'com.apple.security.network.server' entitlement set to YES.");
+            return Err(NetworkingError::ListenFailed(listen_failures));
+        }
+        for address in cfg.external_addrs.iter() {
+            swarm.add_external_address(address.clone());
+        }
         
+        #[cfg(not(target_family="wasm"))]
+        let peers_last_seen = match (cfg.private_key_path.as_ref(), swarm.behaviour_mut().kdht.as_mut()) {
+            (Some(key_path), Some(kdht)) => peer_store::PeerStore::load(&peer_store::path(key_path)).restore(kdht),
+            _ => HashMap::new(),
+        };
+
         let node = Node {
             id: key.public().to_peer_id().to_string(),
             name: cfg.name.clone(),
             capabilities: vec![],
+            resources: cfg.resources.clone(),
         };
 
+        swarm.behaviour_mut().gossipsub.subscribe(&IdentTopic::new(CAPABILITIES_TOPIC))?;
+
+        let (validation_sender, validation_receiver) = channel::<ValidatedMessage>(event::DEFAULT_EVENT_BUFFER_SIZE);
+        let relays = parse_peer_addresses(&cfg.relay_nodes);
+        let mut persistent_peers = HashMap::<PeerId, Vec<Multiaddr>>::new();
+        for (peer_id, address) in parse_peer_addresses(&cfg.bootstrap_nodes).into_iter().chain(relays.iter().cloned()) {
+            persistent_peers.entry(peer_id).or_default().push(address);
+        }
+        // dialed on the first redial tick
+        let redials = persistent_peers.keys().map(|peer_id| (*peer_id, (web_time::Instant::now(), Duration::ZERO))).collect();
         let networking = Libp2p {
             cfg: cfg.clone(),
+            keypair: key,
             // nodes_map: nodes_map,
             swarm: swarm,
             command_receiver: command_receiver,
-            node: node.clone(),
+            node,
             // node_regsiter_topic: topic,
-            event_sender: event_sender,
+            event_bus: event_bus,
+            metrics,
+            counters,
             find_peer_requests: Arc::new(Mutex::new(HashMap::new())),
+            provide_requests: HashMap::new(),
+            find_providers_requests: HashMap::new(),
+            put_record_requests: HashMap::new(),
+            get_record_requests: HashMap::new(),
+            listeners: listener_ids,
+            shutdown_sender: None,
+            #[cfg(not(target_family="wasm"))]
+            metrics_server: None,
+            #[cfg(not(target_family="wasm"))]
+            nat_status: event::Reachability { status: event::NatStatus::Unknown, confidence: 0 },
+            #[cfg(target_family="wasm")]
+            nat_status: event::Reachability { status: event::NatStatus::Private, confidence: cfg.nat.confidence_max.max(cfg.nat.relay_confidence) },
+            nat_status_from_v2: false,
+            topic_subscribers: HashMap::new(),
+            next_subscription_id: 0,
+            validators: HashMap::new(),
+            validation_sender,
+            validation_receiver,
+            maintenance: ticker(MAINTENANCE_INTERVAL),
+            dht_bootstrap: ticker(cfg.dht_bootstrap_interval),
+            announce: ticker(cfg.announce_interval),
+            redial: ticker(REDIAL_CHECK_INTERVAL),
+            relays,
+            relay_listeners: HashMap::new(),
+            persistent_peers,
+            redials,
+            connected_peers: HashMap::new(),
+            dial_requests: HashMap::new(),
+            peer_addresses: HashMap::new(),
+            #[cfg(not(target_family="wasm"))]
+            peers_last_seen,
         };
 
+        Ok(networking)
+    }
+
+    /// Drives the swarm until every listener reported its first address or closed, for at most
+    /// LISTEN_TIMEOUT. Transports that bind in the background (QUIC, WebRTC) only fail here.
+    async fn wait_for_listeners(&mut self) -> Result<(), NetworkingError> {
+        let mut pending = self.listeners.clone();
+        let mut failures = Vec::new();
+        let mut timeout = Box::pin(sleep(LISTEN_TIMEOUT)).fuse();
+        while !pending.is_empty() {
+            let event = futures::select! {
+                event = self.swarm.select_next_some() => event,
+                _ = timeout => {
+                    failures.extend(pending.drain().map(|(_, address)| (address, "timed out".to_string())));
+                    break;
+                }
+            };
+            match &event {
+                SwarmEvent::NewListenAddr { listener_id, .. } => {
+                    pending.remove(listener_id);
+                }
+                SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                    if let Some(address) = pending.remove(listener_id) {
+                        let error = match reason {
+                            Ok(()) => "listener closed".to_string(),
+                            Err(e) => e.to_string(),
+                        };
+                        failures.push((address, error));
+                    }
+                }
+                _ => {}
+            }
+            self.handle_event(event).await;
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(NetworkingError::ListenFailed(failures))
+        }
+    }
+
+    /// Runs the networking loop in the background and returns the local peer id.
+    fn spawn(self) -> String {
+        let id = self.node.id.clone();
         spawn(async move {
-            let _ = networking.run().await;
+            let _ = self.run().await;
         });
-
-        Ok(node)
+        id
     }
 
-    async fn run(mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn run(mut self) -> Result<(), NetworkingError> {
         tracing::info!("Starting networking");
-        
+        self.bootstrap_dht();
+        if self.needs_relays() {
+            self.listen_on_relays();
+        }
+
         #[cfg(not(target_family="wasm"))]
         loop {
             tokio::select! {
                 Some(event) = self.swarm.next() => self.handle_event(event).await,
                 Some(command) = self.command_receiver.next() => self.handle_command(command).await,
+                Some(validated) = self.validation_receiver.next() => self.handle_validated_message(validated),
+                Some(_) = self.maintenance.next() => self.maintain(),
+                Some(_) = self.dht_bootstrap.next() => self.bootstrap_dht(),
+                Some(_) = self.redial.next() => self.redial_lost_peers(),
+                Some(_) = self.announce.next() => self.announce(),
                 else => break,
             }
+            if self.shutdown_sender.is_some() {
+                break;
+            }
         };
 
         #[cfg(target_family="wasm")]
@@ -424,14 +1101,99 @@ impl Libp2p {
             futures::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
                 command = self.command_receiver.select_next_some() => self.handle_command(command).await,
+                validated = self.validation_receiver.select_next_some() => self.handle_validated_message(validated),
+                _ = self.maintenance.select_next_some() => self.maintain(),
+                _ = self.dht_bootstrap.select_next_some() => self.bootstrap_dht(),
+                _ = self.redial.select_next_some() => self.redial_lost_peers(),
+                _ = self.announce.select_next_some() => self.announce(),
                 complete => break,
             }
+            if self.shutdown_sender.is_some() {
+                break;
+            }
         };
 
+        self.shutdown().await;
+        let shutdown_sender = self.shutdown_sender.take();
+        // Dropping the swarm closes whatever is left of the transports and behaviours.
+        drop(self);
+        if let Some(sender) = shutdown_sender {
+            let _ = sender.send(());
+        }
+        tracing::info!("Networking stopped");
+
         Ok(())
     }
+
+    async fn shutdown(&mut self) {
+        #[cfg(not(target_family="wasm"))]
+        self.save_peer_store();
+        #[cfg(not(target_family="wasm"))]
+        if let Some(server) = self.metrics_server.take() {
+            server.abort();
+        }
+
+        // Dropping the senders ends every Subscription stream.
+        self.topic_subscribers.clear();
+        let topics = self.swarm.behaviour().gossipsub.topics().cloned().collect::<Vec<_>>();
+        for topic in topics {
+            if let Err(e) = self.swarm.behaviour_mut().gossipsub.unsubscribe(&IdentTopic::new(topic.to_string())) {
+                tracing::warn!("Failed to unsubscribe from {topic}: {e}");
+            }
+        }
+
+        for (_, sender) in self.find_peer_requests.lock().await.drain() {
+            let _ = sender.send(Err(NetworkingError::ChannelClosed));
+        }
+        for (_, sender) in self.provide_requests.drain() {
+            let _ = sender.send(Err(NetworkingError::ChannelClosed));
+        }
+        for (_, (_, sender)) in self.find_providers_requests.drain() {
+            let _ = sender.send(Err(NetworkingError::ChannelClosed));
+        }
+        for (_, sender) in self.put_record_requests.drain() {
+            let _ = sender.send(Err(NetworkingError::ChannelClosed));
+        }
+        for (_, request) in self.get_record_requests.drain() {
+            let _ = request.sender.send(Err(NetworkingError::ChannelClosed));
+        }
+        for (_, sender) in self.dial_requests.drain() {
+            let _ = sender.send(Err(NetworkingError::ChannelClosed));
+        }
+
+        for (listener, _) in self.listeners.drain() {
+            self.swarm.remove_listener(listener);
+        }
+        for (_, listener) in self.relay_listeners.drain() {
+            self.swarm.remove_listener(listener);
+        }
+
+        let peers = self.swarm.connected_peers().cloned().collect::<Vec<_>>();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+
+        // Keep polling the swarm so that unsubscriptions and connection closes reach the remote peers.
+        let closing = async {
+            while self.swarm.connected_peers().next().is_some() {
+                match self.swarm.next().await {
+                    Some(event) => tracing::debug!("Shutting down: {event:?}"),
+                    None => break,
+                }
+            }
+        };
+        #[cfg(not(target_family="wasm"))]
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, closing).await.is_err() {
+            tracing::warn!("Timed out waiting for connections to close");
+        }
+        #[cfg(target_family="wasm")]
+        if let futures::future::Either::Right(_) = futures::future::select(Box::pin(closing), Box::pin(gloo_timers::future::TimeoutFuture::new(SHUTDOWN_TIMEOUT.as_millis() as u32))).await {
+            tracing::warn!("Timed out waiting for connections to close");
+        }
+    }
     
     async fn handle_event(&mut self, event :SwarmEvent<PosemeshBehaviourEvent>) {
+        self.record_metrics(&event);
         match event {
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Dcutr(dcutr::Event {
                 remote_peer_id,
@@ -465,7 +1227,9 @@ impl Libp2p {
                 let mut found_address = false;
                 for peer in peers {
                     found_address = !peer.addrs.is_empty();
-                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer.peer_id);
+                    if self.cfg.gossipsub.add_explicit_peers {
+                        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer.peer_id);
+                    }
                     for addr in peer.addrs {
                         tracing::info!("Adding address to DHT: {}", addr.clone());
                         self.swarm.behaviour_mut().kdht.as_mut().map(|dht| {
@@ -482,7 +1246,7 @@ impl Libp2p {
                     if found_address {
                         let _ = sender.unwrap().send(Ok(()));
                     } else {
-                        let _ = sender.unwrap().send(Err(Box::new(DialError::NoAddresses)));
+                        let _ = sender.unwrap().send(Err(NetworkingError::NoAddresses(peer_id)));
                     }
                 } else if last {
                     tracing::warn!("No request found for peer: {peer_id}");
@@ -513,6 +1277,82 @@ impl Libp2p {
             )) => {
                 tracing::info!("Bootstrap succeeded");
             }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::StartProviding(result),
+                    ..
+                }
+            )) => {
+                let result = result.map(|_| ()).map_err(|e| NetworkingError::Dht(e.to_string()));
+                match self.provide_requests.remove(&id) {
+                    Some(sender) => {
+                        let _ = sender.send(result);
+                    }
+                    None => if let Err(e) = result {
+                        tracing::warn!("Failed to announce provider record: {e}");
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetProviders(result),
+                    step: ProgressStep { last, .. },
+                    ..
+                }
+            )) => {
+                self.handle_providers(id, result, last);
+            }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::PutRecord(result),
+                    ..
+                }
+            )) => {
+                let result = result.map(|_| ()).map_err(|e| NetworkingError::Dht(e.to_string()));
+                match self.put_record_requests.remove(&id) {
+                    Some(sender) => {
+                        let _ = sender.send(result);
+                    }
+                    None => if let Err(e) = result {
+                        tracing::warn!("Failed to store record: {e}");
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetRecord(result),
+                    step: ProgressStep { last, .. },
+                    ..
+                }
+            )) => {
+                self.handle_record(id, result, last);
+            }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(kad::Event::InboundRequest {
+                request: kad::InboundRequest::PutRecord { source, record: Some(record), .. },
+            })) => {
+                if let Err(e) = record::verify(&record) {
+                    tracing::warn!("Refusing record from {source}: {e}");
+                    return;
+                }
+                if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
+                    if let Err(e) = kdht.store_mut().put(record) {
+                        tracing::warn!("Failed to store record from {source}: {e}");
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(kad::Event::InboundRequest {
+                request: kad::InboundRequest::AddProvider { record: Some(record) },
+            })) => {
+                if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
+                    if let Err(e) = kdht.store_mut().add_provider(record) {
+                        tracing::warn!("Failed to store provider record: {e}");
+                    }
+                }
+            }
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(_)) => {
                 tracing::info!("KDHT event => {event:?}");
             }
@@ -520,79 +1360,152 @@ impl Libp2p {
                 let local_peer_id = *self.swarm.local_peer_id();
                 println!(
                     "Local node is listening on {:?}",
-                    address.with(Protocol::P2p(local_peer_id))
+                    address.clone().with(Protocol::P2p(local_peer_id))
                 );
+                self.send_event(event::Event::ListenAddressAdded { address });
             }
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id, connection_id, endpoint, num_established, ..
             } => {
                 tracing::info!("Connected to {peer_id} on {:?}", endpoint.get_remote_address());
-                self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                self.connected_peers.entry(peer_id).or_default()
+                    .connections.insert(connection_id, endpoint.get_remote_address().clone());
+                if let Some(sender) = self.dial_requests.remove(&connection_id) {
+                    let _ = sender.send(Ok(peer_id));
+                }
+                #[cfg(not(target_family="wasm"))]
+                self.peers_last_seen.insert(peer_id, peer_store::unix_now());
+                self.redials.remove(&peer_id);
+                // the relay dropped our reservation along with the previous connection
+                if self.needs_relays() && self.relays.iter().any(|(relay, _)| *relay == peer_id) {
+                    self.listen_on_relays();
+                }
+                if self.cfg.gossipsub.add_explicit_peers {
+                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                }
+                self.send_event(event::Event::PeerConnected {
+                    peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    num_established: num_established.get(),
+                });
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id, connection_id, num_established, cause, ..
+            } => {
+                tracing::info!("Connection to {peer_id} closed: {cause:?}");
+                if let Some(peer) = self.connected_peers.get_mut(&peer_id) {
+                    peer.connections.remove(&connection_id);
+                }
+                #[cfg(not(target_family="wasm"))]
+                self.peers_last_seen.insert(peer_id, peer_store::unix_now());
+                if num_established == 0 {
+                    self.connected_peers.remove(&peer_id);
+                    self.schedule_redial(peer_id);
+                }
+                self.send_event(event::Event::PeerDisconnected {
+                    peer_id,
+                    num_established,
+                    cause: cause.map(|e| e.to_string()),
+                });
             }
             SwarmEvent::Dialing {
                 peer_id: Some(peer_id),
                 ..
             } => tracing::info!("Dialing {peer_id}"),
+            SwarmEvent::OutgoingConnectionError {
+                connection_id, peer_id, error,
+            } => {
+                match peer_id {
+                    Some(peer_id) if self.persistent_peers.contains_key(&peer_id) => {
+                        tracing::warn!("Failed to dial {peer_id}: {error}");
+                        self.schedule_redial(peer_id);
+                    }
+                    _ => tracing::debug!("Failed to dial {peer_id:?}: {error}"),
+                }
+                if let Some(sender) = self.dial_requests.remove(&connection_id) {
+                    let _ = sender.send(Err(NetworkingError::DialFailure(error)));
+                }
+            }
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                tracing::info!("Listener {listener_id:?} closed: {reason:?}");
+                self.listeners.remove(&listener_id);
+                self.relay_listeners.retain(|_, id| *id != listener_id);
+            }
             #[cfg(not(target_family="wasm"))]
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                 for (peer_id, _multiaddr) in list {
                     tracing::info!("mDNS discovered a new peer: {peer_id}");
-                    self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    if self.cfg.gossipsub.add_explicit_peers {
+                        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    }
                 }
             },
             #[cfg(not(target_family="wasm"))]
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                for (peer_id, _multiaddr) in list {
+                for (peer_id, multiaddr) in list {
                     tracing::info!("mDNS discover peer has expired: {peer_id}");
                     self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                    self.send_event(event::Event::PeerExpired { peer_id, address: multiaddr });
                 }
             },
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
                 message: gossipsub::Message { source, data, topic, .. },
-                ..
             })) => {
-                if let Err(e) = self.event_sender.send(event::Event::PubSubMessageReceivedEvent { 
-                        topic: topic.clone(),
-                        message: data.clone(),
-                        from: source,
-                    }).await {
-                    tracing::error!("Failed to send pubsub message: {e}");
+                let message = event::PubsubMessage { topic, message: data, from: source };
+                if message.topic == IdentTopic::new(CAPABILITIES_TOPIC).hash() {
+                    self.handle_announcement(message_id, propagation_source, message);
+                    return;
+                }
+                match self.validators.get(&message.topic) {
+                    Some(validator) => {
+                        let validation = validator.validate(message.clone());
+                        let mut sender = self.validation_sender.clone();
+                        spawn(async move {
+                            let validation = validation.await;
+                            let _ = sender.send(ValidatedMessage { message_id, propagation_source, message, validation }).await;
+                        });
+                    }
+                    None => self.handle_validated_message(ValidatedMessage {
+                        message_id,
+                        propagation_source,
+                        message,
+                        validation: event::Validation::Accept,
+                    }),
                 }
             },
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Ping(libp2p::ping::Event { peer, result, .. })) => {
+                match result {
+                    Ok(rtt) => if let Some(connected) = self.connected_peers.get_mut(&peer) {
+                        if connected.rtts.len() == RTT_WINDOW {
+                            connected.rtts.pop_front();
+                        }
+                        connected.rtts.push_back(rtt);
+                    }
+                    Err(e) => tracing::debug!("Failed to ping {peer}: {e}"),
+                }
+            }
             // Prints peer id identify info is being sent to.
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::Identify(libp2p::identify::Event::Sent { peer_id, .. })) => {
                 tracing::info!("Sent identify info to {peer_id:?}")
             },
-            SwarmEvent::Behaviour(PosemeshBehaviourEvent::AutonatClient(libp2p::autonat::v2::client::Event {
-                server,
-                tested_addr,
-                bytes_sent,
-                result: Ok(()),
-            })) => {
-                tracing::info!("Tested {tested_addr} with {server}. Sent {bytes_sent} bytes for verification. Everything Ok and verified.");
-                self.swarm.add_external_address(tested_addr.clone());
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Autonat(libp2p::autonat::v1::Event::OutboundProbe(probe))) => {
+                tracing::debug!("AutoNAT probe: {probe:?}");
+                self.update_nat_status();
             }
-            SwarmEvent::Behaviour(PosemeshBehaviourEvent::AutonatClient(libp2p::autonat::v2::client::Event {
-                server,
-                tested_addr,
-                bytes_sent,
-                result: Err(e),
-            })) => {
-                tracing::info!("Tested {tested_addr} with {server}. Sent {bytes_sent} bytes for verification. Failed with {e:?}.");
-
-                for relay in self.cfg.relay_nodes.iter() {
-                    let maddr = Multiaddr::from_str(relay).unwrap();
-                    let addr = maddr
-                        .with(Protocol::P2pCircuit);
-                    match self.swarm.listen_on(addr.clone()) {
-                        Ok(_) => {
-                            tracing::info!("Listening on relay address: {addr}");
-                        },
-                        Err(e) => {
-                            tracing::error!("Failed to listen on relay address: {addr}. Error: {e}");
-                        }
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Autonat(libp2p::autonat::v1::Event::StatusChanged { old, new })) => {
+                tracing::info!("AutoNAT status changed from {old:?} to {new:?}");
+                // configured addresses stay external, whatever AutoNAT thinks of them
+                if let libp2p::autonat::v1::NatStatus::Public(address) = old {
+                    if !self.cfg.external_addrs.contains(&address) {
+                        self.swarm.remove_external_address(&address);
                     }
                 }
+                if let libp2p::autonat::v1::NatStatus::Public(address) = new {
+                    self.swarm.add_external_address(address);
+                }
+                self.update_nat_status();
             }
             SwarmEvent::ExternalAddrConfirmed { address } => {
                 tracing::info!("External address confirmed: {address}");
@@ -603,19 +1516,28 @@ impl Libp2p {
                         dht.set_mode(Some(kad::Mode::Server));
                     }
                 });
+                self.send_event(event::Event::ExternalAddressConfirmed { address });
             }
             SwarmEvent::NewExternalAddrCandidate { address } => {
                 tracing::info!("New external address candidate: {address}");
             }
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::RelayClient(
-                libp2p::relay::client::Event::ReservationReqAccepted { .. },
+                libp2p::relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. },
             )) => {
-                tracing::info!("Relay accepted our reservation request");
+                tracing::info!("Relay {relay_peer_id} accepted our reservation request");
+                self.send_event(event::Event::RelayReservationAccepted { relay_peer_id, renewal });
             }
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::RelayClient(event)) => {
                 tracing::info!("Relay Client: {event:?}");
             }
             #[cfg(not(target_family="wasm"))]
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Relay(event)) => self.handle_relay_server_event(event),
+            #[cfg(not(target_family="wasm"))]
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::AutonatClient(libp2p::autonat::v2::client::Event { server, tested_addr, result, .. })) => {
+                tracing::info!("AutoNAT v2 tested {tested_addr} with {server}: {result:?}");
+                self.handle_v2_probe(tested_addr, result.is_ok());
+            }
+            #[cfg(not(target_family="wasm"))]
             SwarmEvent::Behaviour(PosemeshBehaviourEvent::AutonatServer(libp2p::autonat::v2::server::Event {tested_addr, result, ..})) => {
                 tracing::info!("Autonat Server tested address: {tested_addr}, result: {result:?}");
             }
@@ -636,47 +1558,565 @@ impl Libp2p {
                     }
                 });
 
+                if let Some(peer) = self.connected_peers.get_mut(&peer_id) {
+                    peer.protocols = protocols.iter().map(|p| p.to_string()).collect();
+                    peer.agent_version = Some(agent_version.clone());
+                }
+
                 let node = Node {
                     id: peer_id.to_string(),
                     name: agent_version,
                     capabilities: protocols.iter().map(|p| p.to_string()).filter(|p| !p.contains("posemesh") && !p.contains("libp2p") && !p.contains("ipfs") ).collect::<Vec<String>>(),
+                    resources: None,
                 };
 
-                self.event_sender.send(event::Event::NewNodeRegistered { node: node.clone() }).await.unwrap_or_else(|_| panic!("{}: Failed to send new node: {} registered event", self.node.id, node.name));
+                self.send_event(event::Event::NewNodeRegistered { node });
             },
             e => tracing::debug!("Other events: {e:?}"),
         }
     }
 
+    fn record_metrics(&self, event: &SwarmEvent<PosemeshBehaviourEvent>) {
+        self.metrics.record(event);
+        match event {
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Gossipsub(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Identify(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Kdht(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Dcutr(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Ping(event)) => self.metrics.record(event),
+            #[cfg(not(target_family="wasm"))]
+            SwarmEvent::Behaviour(PosemeshBehaviourEvent::Relay(event)) => self.metrics.record(event),
+            _ => {}
+        }
+    }
+
+    #[cfg(not(target_family="wasm"))]
+    fn handle_relay_server_event(&mut self, event: libp2p::relay::Event) {
+        use libp2p::relay::Event as RelayEvent;
+        match event {
+            RelayEvent::ReservationReqAccepted { src_peer_id, renewed } => {
+                tracing::info!("Accepted relay reservation of {src_peer_id}, renewed: {renewed}");
+                self.send_event(event::Event::RelayServerReservationAccepted { peer_id: src_peer_id, renewed });
+            }
+            RelayEvent::ReservationReqDenied { src_peer_id } => {
+                tracing::info!("Denied relay reservation of {src_peer_id}");
+                self.send_event(event::Event::RelayServerReservationDenied { peer_id: src_peer_id });
+            }
+            RelayEvent::ReservationTimedOut { src_peer_id } => {
+                tracing::info!("Relay reservation of {src_peer_id} timed out");
+                self.send_event(event::Event::RelayServerReservationExpired { peer_id: src_peer_id });
+            }
+            RelayEvent::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                tracing::info!("Relaying {src_peer_id} to {dst_peer_id}");
+                self.send_event(event::Event::RelayServerCircuitOpened { src_peer_id, dst_peer_id });
+            }
+            RelayEvent::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+                tracing::info!("Denied relaying {src_peer_id} to {dst_peer_id}");
+                self.send_event(event::Event::RelayServerCircuitDenied { src_peer_id, dst_peer_id });
+            }
+            RelayEvent::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+                tracing::info!("Closed relay circuit from {src_peer_id} to {dst_peer_id}: {error:?}");
+                self.send_event(event::Event::RelayServerCircuitClosed {
+                    src_peer_id,
+                    dst_peer_id,
+                    error: error.map(|e| e.to_string()),
+                });
+            }
+            event => tracing::debug!("Relay server: {event:?}"),
+        }
+    }
+
+    // Runs every MAINTENANCE_INTERVAL.
+    fn maintain(&mut self) {
+        #[cfg(not(target_family="wasm"))]
+        self.save_peer_store();
+    }
+
+    // Publishes this node on the capabilities topic, once it has resources to announce.
+    fn announce(&mut self) {
+        if self.node.resources.is_none() {
+            return;
+        }
+        let announcement = match serde_json::to_vec(&self.node) {
+            Ok(announcement) => announcement,
+            Err(e) => {
+                tracing::error!("Failed to encode announcement: {e}");
+                return;
+            }
+        };
+        // fails with InsufficientPeers until someone else joins the topic
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(IdentTopic::new(CAPABILITIES_TOPIC), announcement) {
+            tracing::debug!("Failed to announce resources: {e}");
+        }
+    }
+
+    fn handle_announcement(&mut self, message_id: gossipsub::MessageId, propagation_source: PeerId, message: event::PubsubMessage) {
+        // a node may only announce itself
+        let node = serde_json::from_slice::<Node>(&message.message).ok()
+            .filter(|node| message.from.is_some_and(|from| from.to_string() == node.id));
+        let acceptance = match node {
+            Some(_) => gossipsub::MessageAcceptance::Accept,
+            None => gossipsub::MessageAcceptance::Reject,
+        };
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance) {
+            tracing::warn!("Failed to forward announcement {message_id}: {e}");
+        }
+        match node {
+            Some(node) => self.send_event(event::Event::NewNodeRegistered { node }),
+            None => tracing::warn!("Rejected invalid announcement from {propagation_source}"),
+        }
+    }
+
+    fn bootstrap_dht(&mut self) {
+        if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
+            if let Err(e) = kdht.bootstrap() {
+                tracing::debug!("Skipping DHT bootstrap: {e}");
+            }
+        }
+    }
+
+    // Runs every REDIAL_CHECK_INTERVAL and dials the persistent peers whose backoff has elapsed.
+    fn redial_lost_peers(&mut self) {
+        let now = web_time::Instant::now();
+        let due = self.redials.iter()
+            .filter(|(_, (at, _))| *at <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in due {
+            if self.swarm.is_connected(&peer_id) {
+                self.redials.remove(&peer_id);
+                continue;
+            }
+            let addresses = self.persistent_peers.get(&peer_id).cloned().unwrap_or_default();
+            match self.swarm.dial(DialOpts::peer_id(peer_id).addresses(addresses).build()) {
+                Ok(_) => {
+                    // the outcome of the dial reschedules it, this only guards against a dial that never returns
+                    if let Some((at, _)) = self.redials.get_mut(&peer_id) {
+                        *at = now + self.cfg.max_redial_backoff;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to dial {peer_id}: {e}");
+                    self.schedule_redial(peer_id);
+                }
+            }
+        }
+    }
+
+    fn schedule_redial(&mut self, peer_id: PeerId) {
+        if !self.persistent_peers.contains_key(&peer_id) {
+            return;
+        }
+        let previous = self.redials.get(&peer_id).map(|(_, backoff)| *backoff).unwrap_or_default();
+        let backoff = (previous * 2).max(self.cfg.redial_backoff).min(self.cfg.max_redial_backoff);
+        tracing::info!("Dialing {peer_id} again in {backoff:?}");
+        self.redials.insert(peer_id, (web_time::Instant::now() + backoff, backoff));
+    }
+
+    // Reserves a slot on every relay we don't hold a reservation with yet.
+    fn listen_on_relays(&mut self) {
+        for (relay_peer_id, maddr) in self.relays.clone() {
+            if self.relay_listeners.contains_key(&relay_peer_id) {
+                continue;
+            }
+            let addr = maddr.with(Protocol::P2pCircuit);
+            match self.swarm.listen_on(addr.clone()) {
+                Ok(id) => {
+                    self.relay_listeners.insert(relay_peer_id, id);
+                    tracing::info!("Listening on relay address: {addr}");
+                },
+                Err(e) => {
+                    tracing::error!("Failed to listen on relay address: {addr}. Error: {e}");
+                }
+            }
+        }
+    }
+
+    #[cfg(not(target_family="wasm"))]
+    fn save_peer_store(&mut self) {
+        let Some(key_path) = self.cfg.private_key_path.clone() else {
+            return;
+        };
+        // peers that are still connected are seen right now
+        let now = peer_store::unix_now();
+        for peer_id in self.connected_peers.keys() {
+            self.peers_last_seen.insert(*peer_id, now);
+        }
+        let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() else {
+            return;
+        };
+        let store = peer_store::PeerStore::capture(kdht, &self.peers_last_seen);
+        if let Err(e) = store.save(&peer_store::path(&key_path)) {
+            tracing::warn!("Failed to save peer store: {e}");
+        }
+    }
+
+    fn handle_validated_message(&mut self, validated: ValidatedMessage) {
+        let ValidatedMessage { message_id, propagation_source, message, validation } = validated;
+        let acceptance = match validation {
+            event::Validation::Accept => gossipsub::MessageAcceptance::Accept,
+            event::Validation::Reject => gossipsub::MessageAcceptance::Reject,
+            event::Validation::Ignore => gossipsub::MessageAcceptance::Ignore,
+        };
+        self.counters.message_received(match validation {
+            event::Validation::Accept => "accept",
+            event::Validation::Reject => "reject",
+            event::Validation::Ignore => "ignore",
+        });
+        // Forwarding may fail, e.g. when no mesh peer is left; the message is still delivered locally.
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance) {
+            tracing::warn!("Failed to forward message {message_id} on {}: {e}", message.topic);
+        }
+        if validation != event::Validation::Accept {
+            tracing::debug!("Message {message_id} on {} from {propagation_source} not accepted: {validation:?}", message.topic);
+            return;
+        }
+
+        self.deliver_message(message.clone());
+        self.send_event(event::Event::PubSubMessageReceivedEvent {
+            topic: message.topic,
+            message: message.message,
+            from: message.from,
+        });
+    }
+
+    fn handle_providers(&mut self, id: QueryId, result: kad::GetProvidersResult, last: bool) {
+        let Some((providers, _)) = self.find_providers_requests.get_mut(&id) else {
+            return;
+        };
+        let error = match result {
+            Ok(kad::GetProvidersOk::FoundProviders { providers: found, .. }) => {
+                providers.extend(found);
+                None
+            }
+            Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => None,
+            Err(e) => Some(e),
+        };
+        if !last && error.is_none() {
+            return;
+        }
+
+        let Some((providers, sender)) = self.find_providers_requests.remove(&id) else {
+            return;
+        };
+        // A query that timed out after finding some providers is still useful.
+        let result = match error {
+            Some(e) if providers.is_empty() => Err(NetworkingError::Dht(e.to_string())),
+            _ => Ok(providers.into_iter().collect()),
+        };
+        let _ = sender.send(result);
+    }
+
+    fn handle_record(&mut self, id: QueryId, result: kad::GetRecordResult, last: bool) {
+        let Some(request) = self.get_record_requests.get_mut(&id) else {
+            return;
+        };
+        let error = match result {
+            Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer, record: found })) => {
+                match record::verify(&found) {
+                    Ok(verified) => request.found.push(verified),
+                    Err(e) => tracing::warn!("Ignoring record {} from {peer:?}: {e}", request.key),
+                }
+                None
+            }
+            Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        // a failed query, e.g. one that timed out, has found all it will
+        let finished = last || error.is_some();
+        let agreed = request.agreed(finished);
+        if agreed.is_none() && !finished {
+            return;
+        }
+
+        let Some(request) = self.get_record_requests.remove(&id) else {
+            return;
+        };
+        if !last {
+            if let Some(mut query) = self.swarm.behaviour_mut().kdht.as_mut().and_then(|kdht| kdht.query_mut(&id)) {
+                query.finish();
+            }
+        }
+        let result = match agreed {
+            Some(agreed) => Ok(agreed),
+            None => Err(NetworkingError::Dht(error.unwrap_or_else(|| match request.found.len() {
+                0 => format!("No peer returned {}", request.key),
+                n => format!("The {n} peers that returned {} don't agree on {:?} of them", request.key, request.quorum),
+            }))),
+        };
+        let _ = request.sender.send(result);
+    }
+
+    fn send_event(&self, event: event::Event) {
+        self.event_bus.publish(event);
+    }
+
+    // Reads the status tracked by AutoNAT v1 after every probe.
+    fn update_nat_status(&mut self) {
+        let Some(autonat) = self.swarm.behaviour().autonat.as_ref() else {
+            return;
+        };
+        let status = match autonat.nat_status() {
+            libp2p::autonat::v1::NatStatus::Public(address) => event::NatStatus::Public(address),
+            libp2p::autonat::v1::NatStatus::Private => event::NatStatus::Private,
+            libp2p::autonat::v1::NatStatus::Unknown => event::NatStatus::Unknown,
+        };
+        if status == event::NatStatus::Unknown && self.nat_status_from_v2 {
+            return;
+        }
+        let reachability = event::Reachability { status, confidence: autonat.confidence() };
+        self.nat_status_from_v2 = false;
+        self.set_nat_status(reachability);
+    }
+
+    // Relays from before AutoNAT v1 support only answer v2 probes. Their results decide the
+    // status until a v1 server answers.
+    #[cfg(not(target_family="wasm"))]
+    fn handle_v2_probe(&mut self, tested_addr: Multiaddr, reachable: bool) {
+        if reachable {
+            self.swarm.add_external_address(tested_addr.clone());
+        }
+        let v1_status = self.swarm.behaviour().autonat.as_ref().map(|autonat| autonat.nat_status());
+        if !matches!(v1_status, None | Some(libp2p::autonat::v1::NatStatus::Unknown)) {
+            return;
+        }
+        let status = if reachable { event::NatStatus::Public(tested_addr) } else { event::NatStatus::Private };
+        let confidence = if self.nat_status.status == status {
+            (self.nat_status.confidence + 1).min(self.cfg.nat.confidence_max)
+        } else {
+            0
+        };
+        self.nat_status_from_v2 = true;
+        self.set_nat_status(event::Reachability { status, confidence });
+    }
+
+    // Listens on the relays once the node is confidently private, or drops the relay listeners
+    // once it is confidently public.
+    fn set_nat_status(&mut self, reachability: event::Reachability) {
+        if self.nat_status == reachability {
+            return;
+        }
+        tracing::info!("NAT status changed from {:?} to {:?}", self.nat_status, reachability);
+        self.nat_status = reachability.clone();
+        self.send_event(event::Event::NatStatusChanged { status: reachability.status, confidence: reachability.confidence });
+
+        if self.needs_relays() {
+            self.listen_on_relays();
+        } else if matches!(self.nat_status.status, event::NatStatus::Public(_)) && self.nat_status.confidence >= self.cfg.nat.relay_confidence {
+            for (relay_peer_id, listener) in self.relay_listeners.drain() {
+                tracing::info!("Publicly reachable, no longer listening on relay {relay_peer_id}");
+                self.swarm.remove_listener(listener);
+            }
+        }
+    }
+
+    // Dials a peer on the addresses given to add_peer_address, since neither the stream behaviour
+    // nor a disabled DHT knows them. Resolves once the connection is established or failed.
+    fn dial_known_peer(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) -> BoxFuture<'static, Result<(), NetworkingError>> {
+        let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+        let connection_id = opts.connection_id();
+        if let Err(e) = self.swarm.dial(opts) {
+            return futures::future::ready(Err(NetworkingError::DialFailure(e))).boxed();
+        }
+        let (sender, receiver) = oneshot::channel::<Result<PeerId, NetworkingError>>();
+        self.dial_requests.insert(connection_id, sender);
+        receiver.map(|res| match res {
+            Ok(res) => res.map(|_| ()),
+            Err(_) => Err(NetworkingError::ChannelClosed),
+        }).boxed()
+    }
+
+    fn needs_relays(&self) -> bool {
+        self.nat_status.status == event::NatStatus::Private && self.nat_status.confidence >= self.cfg.nat.relay_confidence
+    }
+
     async fn handle_command(&mut self, command: client::Command) {
+        self.counters.command(command.kind());
         match command {
             client::Command::Send { message, peer_id, protocol, response } => {
                 let ctrl = self.swarm.behaviour_mut().streams.new_control();
                 let mut receiver = None;
                 if !Swarm::is_connected(&self.swarm, &peer_id) {
-                    tracing::info!("Peer {peer_id} is not connected, trying to find it");
-                    receiver = Some(self.find_peer(peer_id).await);
+                    receiver = Some(match self.peer_addresses.get(&peer_id) {
+                        Some(addresses) => self.dial_known_peer(peer_id, addresses.clone()),
+                        None => {
+                            tracing::info!("Peer {peer_id} is not connected, trying to find it");
+                            let found = self.find_peer(peer_id).await;
+                            found.map(|res| res.unwrap_or_else(|_| {
+                                tracing::debug!("Cancelled finding peer");
+                                Ok(())
+                            })).boxed()
+                        }
+                    });
                 }
+                let counters = self.counters.clone();
                 #[cfg(target_family="wasm")]
-                wasm_bindgen_futures::spawn_local(open_stream(ctrl, peer_id, protocol, message, response, receiver));
+                wasm_bindgen_futures::spawn_local(open_stream(ctrl, peer_id, protocol, message, response, receiver, counters));
 
                 #[cfg(not(target_family="wasm"))]
-                tokio::spawn(open_stream(ctrl, peer_id, protocol, message, response, receiver));
+                tokio::spawn(open_stream(ctrl, peer_id, protocol, message, response, receiver, counters));
             },
             client::Command::SetStreamHandler { protocol, sender } => {
                 self.add_stream_protocol(protocol, sender);
             }
-            client::Command::Subscribe { topic, resp } => {
-                self.subscribe(topic, resp);
+            client::Command::Subscribe { topic, sender, resp } => {
+                self.subscribe(topic, sender, resp);
+            }
+            client::Command::Unsubscribe { topic, id, resp } => {
+                let res = self.unsubscribe(topic, id);
+                if let Some(resp) = resp {
+                    let _ = resp.send(res);
+                } else if let Err(e) = res {
+                    tracing::warn!("Failed to unsubscribe: {e}");
+                }
+            }
+            client::Command::SetTopicValidator { topic, validator, sender } => {
+                let topic = IdentTopic::new(topic).hash();
+                match validator {
+                    Some(validator) => self.validators.insert(topic, validator),
+                    None => self.validators.remove(&topic),
+                };
+                let _ = sender.send(());
+            }
+            client::Command::BanPeer { peer_id, sender } => {
+                tracing::info!("Banning {peer_id}");
+                let behaviour = self.swarm.behaviour_mut();
+                behaviour.blocked_peers.block_peer(peer_id);
+                behaviour.gossipsub.blacklist_peer(&peer_id);
+                let _ = sender.send(());
+            }
+            client::Command::UnbanPeer { peer_id, sender } => {
+                tracing::info!("Unbanning {peer_id}");
+                let behaviour = self.swarm.behaviour_mut();
+                behaviour.blocked_peers.unblock_peer(peer_id);
+                behaviour.gossipsub.remove_blacklisted_peer(&peer_id);
+                let _ = sender.send(());
+            }
+            client::Command::Provide { capability, sender } => {
+                let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() else {
+                    let _ = sender.send(Err(kdht_disabled()));
+                    return;
+                };
+                match kdht.start_providing(RecordKey::new(&capability)) {
+                    Ok(query_id) => {
+                        self.provide_requests.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(NetworkingError::Dht(e.to_string())));
+                    }
+                }
+            }
+            client::Command::StopProviding { capability, sender } => {
+                let res = match self.swarm.behaviour_mut().kdht.as_mut() {
+                    Some(kdht) => {
+                        kdht.stop_providing(&RecordKey::new(&capability));
+                        Ok(())
+                    }
+                    None => Err(kdht_disabled()),
+                };
+                let _ = sender.send(res);
+            }
+            client::Command::FindProviders { capability, sender } => {
+                let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() else {
+                    let _ = sender.send(Err(kdht_disabled()));
+                    return;
+                };
+                let query_id = kdht.get_providers(RecordKey::new(&capability));
+                self.find_providers_requests.insert(query_id, (HashSet::new(), sender));
+            }
+            client::Command::PutRecord { key, value, ttl, quorum, sender } => {
+                let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() else {
+                    let _ = sender.send(Err(kdht_disabled()));
+                    return;
+                };
+                let mut signed = match record::sign(&self.keypair, &key, value) {
+                    Ok(signed) => signed,
+                    Err(e) => {
+                        let _ = sender.send(Err(NetworkingError::InvalidMessage(e)));
+                        return;
+                    }
+                };
+                signed.expires = ttl.map(|ttl| web_time::Instant::now() + ttl);
+                match kdht.put_record(signed, quorum) {
+                    Ok(query_id) => {
+                        self.put_record_requests.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(NetworkingError::Dht(e.to_string())));
+                    }
+                }
+            }
+            client::Command::GetRecord { key, quorum, sender } => {
+                let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() else {
+                    let _ = sender.send(Err(kdht_disabled()));
+                    return;
+                };
+                let query_id = kdht.get_record(RecordKey::new(&key));
+                self.get_record_requests.insert(query_id, GetRecordRequest { key, quorum, found: vec![], sender });
+            }
+            client::Command::SetResources { resources, sender } => {
+                self.node.resources = Some(resources);
+                self.announce();
+                let _ = sender.send(());
+            }
+            client::Command::Dial { address, sender } => {
+                let opts = DialOpts::from(address);
+                let connection_id = opts.connection_id();
+                match self.swarm.dial(opts) {
+                    Ok(_) => {
+                        self.dial_requests.insert(connection_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(NetworkingError::DialFailure(e)));
+                    }
+                }
+            }
+            client::Command::Disconnect { peer_id, sender } => {
+                if self.swarm.disconnect_peer_id(peer_id).is_err() {
+                    tracing::debug!("Not connected to {peer_id}");
+                }
+                let _ = sender.send(());
+            }
+            client::Command::AddPeerAddress { peer_id, address, sender } => {
+                self.swarm.add_peer_address(peer_id, address.clone());
+                if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
+                    kdht.add_address(&peer_id, address.clone());
+                }
+                let addresses = self.peer_addresses.entry(peer_id).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+                let _ = sender.send(());
+            }
+            client::Command::ExportKey { passphrase, sender } => {
+                let _ = sender.send(keys::export(&self.keypair, passphrase.as_deref()));
+            }
+            client::Command::NatStatus { sender } => {
+                let _ = sender.send(self.nat_status.clone());
+            }
+            client::Command::PeerInfo { peer_id, sender } => {
+                let _ = sender.send(self.connected_peers.get(&peer_id).map(|peer| peer.info(peer_id)));
+            }
+            client::Command::ConnectedPeers { sender } => {
+                let _ = sender.send(self.connected_peers.iter().map(|(peer_id, peer)| peer.info(*peer_id)).collect());
+            }
+            client::Command::ListenAddresses { sender } => {
+                let _ = sender.send(self.swarm.listeners().cloned().collect());
+            }
+            client::Command::ExternalAddresses { sender } => {
+                let _ = sender.send(self.swarm.external_addresses().cloned().collect());
+            }
+            client::Command::Shutdown { sender } => {
+                tracing::info!("Shutting down networking");
+                self.shutdown_sender = Some(sender);
             }
             client::Command::Publish { topic, message, sender } => {
                 let t = IdentTopic::new(topic);
                 let res = self.swarm.behaviour_mut().gossipsub.publish(t, message);
-                if res.is_err() {
-                    let _ = sender.send(Err(Box::new(res.err().unwrap())));
-                    return;
+                if res.is_ok() {
+                    self.counters.message_published();
                 }
-                let _ = sender.send(Ok(()));
+                let _ = sender.send(res.map(|_| ()).map_err(NetworkingError::from));
             }
         }
     }
@@ -687,22 +2127,64 @@ impl Libp2p {
     //     Ok(())
     // }
 
-    fn subscribe(&mut self, topic: String, sender: oneshot::Sender<Box<dyn Error + Send + Sync>>) {
+    fn subscribe(&mut self, topic: String, sender: mpsc::Sender<event::PubsubMessage>, resp: oneshot::Sender<Result<u64, NetworkingError>>) {
+        let t = IdentTopic::new(topic.clone());
+
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(&t) {
+            let _ = resp.send(Err(NetworkingError::from(e)));
+            return;
+        }
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.topic_subscribers.entry(t.hash()).or_default().insert(id, sender);
+
+        // The caller went away before getting its Subscription, so nobody will drop it.
+        if resp.send(Ok(id)).is_err() {
+            let _ = self.unsubscribe(topic, Some(id));
+        }
+    }
+
+    // Removes one subscription, or all of them when id is None, and leaves the topic once none is left.
+    // Leaving the topic also drops its validator.
+    fn unsubscribe(&mut self, topic: String, id: Option<u64>) -> Result<(), NetworkingError> {
         let t = IdentTopic::new(topic);
-        
-        match self.swarm.behaviour_mut().gossipsub.subscribe(&t) {
-            Ok(_) => {},
-            Err(e) => {
-                let _ = sender.send(Box::new(e));
+        let hash = t.hash();
+        if let (Some(id), Some(subscribers)) = (id, self.topic_subscribers.get_mut(&hash)) {
+            subscribers.remove(&id);
+            if !subscribers.is_empty() {
+                return Ok(());
             }
-        } 
+        }
+        self.topic_subscribers.remove(&hash);
+        self.validators.remove(&hash);
+        self.swarm.behaviour_mut().gossipsub.unsubscribe(&t)?;
+        Ok(())
     }
 
-    fn add_stream_protocol(&mut self, protocol: StreamProtocol, sender: oneshot::Sender<Result<IncomingStreams, Box<dyn Error + Send + Sync>>>) {
+    fn deliver_message(&mut self, message: event::PubsubMessage) {
+        let Some(subscribers) = self.topic_subscribers.get_mut(&message.topic) else {
+            return;
+        };
+        subscribers.retain(|id, sender| match sender.try_send(message.clone()) {
+            Ok(_) => true,
+            Err(e) if e.is_full() => {
+                tracing::warn!("Subscription {id} to {} is full, dropping message", message.topic);
+                true
+            }
+            Err(_) => false,
+        });
+        if subscribers.is_empty() {
+            if let Err(e) = self.unsubscribe(message.topic.to_string(), None) {
+                tracing::warn!("Failed to unsubscribe from {}: {e}", message.topic);
+            }
+        }
+    }
+
+    fn add_stream_protocol(&mut self, protocol: StreamProtocol, sender: oneshot::Sender<Result<IncomingStreams, NetworkingError>>) {
         let proto = protocol.clone();
         let protocol_ctrl = self.swarm.behaviour_mut().streams.new_control().accept(protocol);
         if protocol_ctrl.is_err() {
-            let _ = sender.send(Err(Box::new(protocol_ctrl.err().unwrap())));
+            let _ = sender.send(Err(NetworkingError::ProtocolAlreadyRegistered(proto)));
             return;
         }
         let incoming_stream = protocol_ctrl.unwrap();
@@ -712,13 +2194,21 @@ impl Libp2p {
         node.capabilities.push(proto.to_string());
 
         self.node = node;
-        self.event_sender.try_send(event::Event::NewNodeRegistered { node: self.node.clone() }).unwrap();
+        self.send_event(event::Event::NewNodeRegistered { node: self.node.clone() });
+        self.announce();
+
+        // Lets nodes that never connected to us find the protocol through the DHT.
+        if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
+            if let Err(e) = kdht.start_providing(RecordKey::new(&proto.to_string())) {
+                tracing::warn!("Failed to announce {proto}: {e}");
+            }
+        }
 
         let _ = sender.send(Ok(incoming_stream));
     }
 
-    async fn find_peer(&mut self, peer_id: PeerId) -> oneshot::Receiver<Result<(), Box<dyn Error + Send + Sync>>> {
-        let (sender, receiver) = oneshot::channel::<Result<(), Box<dyn Error + Send + Sync>>>();
+    async fn find_peer(&mut self, peer_id: PeerId) -> oneshot::Receiver<Result<(), NetworkingError>> {
+        let (sender, receiver) = oneshot::channel::<Result<(), NetworkingError>>();
         let mut find_peer_requests_lock = self.find_peer_requests.lock().await;
 
         if let Some(kdht) = self.swarm.behaviour_mut().kdht.as_mut() {
@@ -730,21 +2220,21 @@ impl Libp2p {
     }
 }
 
-async fn _open_stream(mut ctrl: stream::Control, peer_id: PeerId, protocol: StreamProtocol, message: Vec<u8>) -> Result<Stream, Box<dyn Error + Send + Sync>> {
+async fn _open_stream(mut ctrl: stream::Control, peer_id: PeerId, protocol: StreamProtocol, message: Vec<u8>) -> Result<Stream, NetworkingError> {
     let mut s = ctrl.open_stream(peer_id, protocol).await?;
 
     if !message.is_empty() {
         match s.write(&message[..1]).await {
             Ok(0) => {
                 tracing::warn!("Failed to send message: check warnings");
-                return Err(Box::new(io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset")));
+                return Err(NetworkingError::StreamReset);
             }
             Ok(_) => {
                 s.write_all(&message[1..]).await?;
             }
             Err(e) => {
                 tracing::warn!("Failed to send message: {:?}", e);
-                return Err(Box::new(e));
+                return Err(NetworkingError::from(e));
             }
         }
         s.flush().await?;
@@ -752,24 +2242,24 @@ async fn _open_stream(mut ctrl: stream::Control, peer_id: PeerId, protocol: Stre
     Ok(s)
 }
 
-async fn open_stream(ctrl: stream::Control, peer_id: PeerId, protocol: StreamProtocol, message: Vec<u8>, send_response: oneshot::Sender<Result<Stream, Box<dyn Error + Send + Sync>>>, find_peer_receiver: Option<oneshot::Receiver<Result<(), Box<dyn Error + Send + Sync>>>>) {
-    if let Some(receiver) = find_peer_receiver {
-        match receiver.await {
-            Ok(Ok(_)) => {
+// `connect` finds or dials the peer first when it isn't connected.
+async fn open_stream(ctrl: stream::Control, peer_id: PeerId, protocol: StreamProtocol, message: Vec<u8>, send_response: oneshot::Sender<Result<Stream, NetworkingError>>, connect: Option<BoxFuture<'static, Result<(), NetworkingError>>>, counters: metrics::Counters) {
+    if let Some(connect) = connect {
+        match connect.await {
+            Ok(_) => {
                 tracing::info!("Peer found");
             }
-            Ok(Err(e)) => {
+            Err(e) => {
+                counters.stream_opened(false);
                 if let Err(e) = send_response.send(Err(e)) {
                     tracing::error!("Failed to send feedback: {:?}", e);
                 }
                 return;
             }
-            Err(_) => {
-                tracing::debug!("Cancelled finding peer");
-            }
         }
     }
     let s = retry_with_delay(|| Box::pin(_open_stream(ctrl.clone(), peer_id, protocol.clone(), message.clone())), 3, Duration::from_secs(5)).await;
+    counters.stream_opened(s.is_ok());
     if let Err(e) = s {
         tracing::error!("Failed to open stream: {:?}", e);
         if let Err(send_err) = send_response.send(Err(e)) {
@@ -787,7 +2277,8 @@ async fn open_stream(ctrl: stream::Control, peer_id: PeerId, protocol: StreamPro
 #[derive(Clone)]
 pub struct Networking {
     pub client: Client,
-    pub event_receiver: Arc<Mutex<Receiver<event::Event>>>,
+    events: event::EventBus,
+    metrics: Arc<std::sync::Mutex<Registry>>,
     pub id: String,
 }
 
@@ -799,24 +2290,51 @@ impl Debug for Networking {
     }
 }
 
-async fn initialize_libp2p(cfg: &NetworkingConfig, receiver: mpsc::Receiver<client::Command>, event_sender: mpsc::Sender<event::Event>) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let res = Libp2p::new(cfg, receiver, event_sender).await;
-    match res {
-        Ok(node) => Ok(node.id),
-        Err(e) => Err(e),
+impl Networking {
+    /// Subscribes to all networking events. Every call returns an independent stream;
+    /// events published before the call are not replayed.
+    pub fn events(&self) -> event::EventStream {
+        self.events_with(event::EventFilter::all(), event::DEFAULT_EVENT_BUFFER_SIZE)
     }
-}
 
-impl Networking {
-    pub fn new(cfg: &NetworkingConfig) -> Result<Self, Box<dyn Error + Send + Sync>> {
+    /// Subscribes to the events accepted by `filter`, buffering at most `buffer` of them.
+    /// When the buffer is full, new events are dropped and reported as [`event::Lagged`].
+    pub fn events_with(&self, filter: event::EventFilter, buffer: usize) -> event::EventStream {
+        self.events.subscribe(filter, buffer)
+    }
+
+    /// Encodes the libp2p metrics (connections, bandwidth, gossipsub, Kademlia...) and the counters
+    /// of the networking loop in the OpenMetrics text format.
+    pub fn metrics(&self) -> String {
+        metrics::encode_registry(&self.metrics)
+    }
+
+    /// Stops the networking loop: unsubscribes from all topics, closes listeners and connections
+    /// and fails pending peer lookups. Resolves once the loop has terminated.
+    pub async fn shutdown(&self) -> Result<(), NetworkingError> {
+        self.client.clone().shutdown().await
+    }
+
+    /// Starts the networking loop and resolves once every listener is bound. If some listeners
+    /// fail, nothing is started and [`NetworkingError::ListenFailed`] lists each of them.
+    pub async fn start(cfg: &NetworkingConfig) -> Result<Self, NetworkingError> {
         let (sender, receiver) = channel::<client::Command>(8);
-        let (event_sender, event_receiver) = channel::<event::Event>(8);
-        let cfg = cfg.clone();
+        let events = event::EventBus::default();
+        let metrics = Arc::new(std::sync::Mutex::new(Registry::default()));
         let client = Client::new(sender);
-        
-        let id_res = block_on(initialize_libp2p(&cfg, receiver, event_sender));
 
-        let id = match id_res {
+        let res = async {
+            let mut node = Libp2p::new(cfg, receiver, events.clone(), metrics.clone()).await?;
+            node.wait_for_listeners().await?;
+            // only once the node is sure to start, a failed start would keep the port bound
+            #[cfg(not(target_family="wasm"))]
+            if let Some(address) = cfg.metrics_address {
+                node.metrics_server = Some(metrics::serve(address, metrics.clone())?);
+            }
+            Ok::<_, NetworkingError>(node.spawn())
+        }.await;
+
+        let id = match res {
             Ok(id) => id,
             Err(e) => {
                 tracing::error!("Failed to initialize libp2p: {:?}", e);
@@ -826,8 +2344,26 @@ impl Networking {
 
         Ok(Networking {
             client,
-            event_receiver: Arc::new(Mutex::new(event_receiver)),
+            events,
+            metrics,
             id,
         })
     }
+
+    /// Blocking version of [`Networking::start`]. The node is started on the global runtime, so
+    /// this works from any thread, but prefer `start` from async code.
+    #[cfg(not(target_family="wasm"))]
+    pub fn new(cfg: &NetworkingConfig) -> Result<Self, NetworkingError> {
+        let cfg = cfg.clone();
+        runtime::block_on(async move { Self::start(&cfg).await })
+    }
+
+    /// Synchronous version of [`Networking::start`]. The browser can't be blocked, so this only
+    /// works while the node has no listener to wait for, as is the case for browser transports.
+    #[cfg(target_family="wasm")]
+    pub fn new(cfg: &NetworkingConfig) -> Result<Self, NetworkingError> {
+        Self::start(cfg).now_or_never().unwrap_or_else(|| {
+            Err(NetworkingError::InvalidConfig("listeners can't be awaited synchronously, use Networking::start".to_string()))
+        })
+    }
 }
@@ -0,0 +1,111 @@
+//! Counters of the networking loop, registered next to the libp2p metrics, and the optional HTTP
+//! endpoint that exposes them in the OpenMetrics text format.
+
+use prometheus_client::{encoding::text::encode, metrics::{counter::Counter, family::Family}, registry::Registry};
+use std::sync::Mutex;
+
+#[cfg(not(target_family="wasm"))]
+use std::{net::SocketAddr, sync::Arc};
+#[cfg(not(target_family="wasm"))]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone)]
+pub(crate) struct Counters {
+    commands: Family<Labels, Counter>,
+    streams_opened: Counter,
+    stream_failures: Counter,
+    messages_published: Counter,
+    messages_received: Family<Labels, Counter>,
+}
+
+impl Counters {
+    pub(crate) fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("posemesh");
+        let counters = Counters {
+            commands: Family::default(),
+            streams_opened: Counter::default(),
+            stream_failures: Counter::default(),
+            messages_published: Counter::default(),
+            messages_received: Family::default(),
+        };
+        registry.register("commands", "Client commands handled by the networking loop", counters.commands.clone());
+        registry.register("streams_opened", "Outbound streams opened", counters.streams_opened.clone());
+        registry.register("stream_failures", "Outbound streams that could not be opened", counters.stream_failures.clone());
+        registry.register("messages_published", "Pubsub messages published by this node", counters.messages_published.clone());
+        registry.register("messages_received", "Pubsub messages received, by validation result", counters.messages_received.clone());
+        counters
+    }
+
+    pub(crate) fn command(&self, kind: &str) {
+        self.commands.get_or_create(&vec![("command".to_string(), kind.to_string())]).inc();
+    }
+
+    pub(crate) fn stream_opened(&self, success: bool) {
+        if success {
+            self.streams_opened.inc();
+        } else {
+            self.stream_failures.inc();
+        }
+    }
+
+    pub(crate) fn message_published(&self) {
+        self.messages_published.inc();
+    }
+
+    pub(crate) fn message_received(&self, validation: &str) {
+        self.messages_received.get_or_create(&vec![("validation".to_string(), validation.to_string())]).inc();
+    }
+}
+
+pub(crate) fn encode_registry(registry: &Mutex<Registry>) -> String {
+    let mut buffer = String::new();
+    if let Err(e) = encode(&mut buffer, &registry.lock().unwrap()) {
+        tracing::error!("Failed to encode metrics: {e}");
+    }
+    buffer
+}
+
+/// Answers every request on `address` with the current metrics until the returned task is
+/// aborted. Binding happens right away so that a busy port is reported to the caller.
+#[cfg(not(target_family="wasm"))]
+pub(crate) fn serve(address: SocketAddr, registry: Arc<Mutex<Registry>>) -> std::io::Result<tokio::task::JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    tracing::info!("Serving metrics on http://{address}/metrics");
+
+    Ok(tokio::spawn(async move {
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Failed to serve metrics: {e}");
+                return;
+            }
+        };
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!("Failed to accept metrics connection: {e}");
+                    continue;
+                }
+            };
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                // the request itself doesn't matter, there is a single resource
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let body = encode_registry(&registry);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body,
+                );
+                if let Err(e) = socket.write_all(response.as_bytes()).await {
+                    tracing::debug!("Failed to send metrics: {e}");
+                }
+            });
+        }
+    }))
+}
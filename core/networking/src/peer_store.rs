@@ -0,0 +1,136 @@
+//! Persists the Kademlia routing table and records next to the node's private key, so that a
+//! restarted node finds its peers again without going through the bootstrap nodes.
+
+use libp2p::{kad::{self, store::{MemoryStore, RecordStore}, Record, RecordKey}, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+// appended to the key file path, so that nodes sharing a directory keep their own peers
+const PEER_STORE_SUFFIX: &str = ".peers.json";
+// peers that weren't connected for this long are forgotten
+const PEER_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: PeerId,
+    addresses: Vec<Multiaddr>,
+    // unix timestamp in seconds
+    last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<PeerId>,
+    // unix timestamp in seconds
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct PeerStore {
+    peers: Vec<StoredPeer>,
+    records: Vec<StoredRecord>,
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+pub(crate) fn path(private_key_path: &str) -> PathBuf {
+    PathBuf::from(format!("{private_key_path}{PEER_STORE_SUFFIX}"))
+}
+
+impl PeerStore {
+    pub(crate) fn load(path: &Path) -> Self {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring corrupt peer store {}: {e}", path.display());
+                PeerStore::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => PeerStore::default(),
+            Err(e) => {
+                tracing::warn!("Failed to read peer store {}: {e}", path.display());
+                PeerStore::default()
+            }
+        }
+    }
+
+    /// Adds the peers and records that haven't expired to the DHT and returns when each peer was last seen.
+    pub(crate) fn restore(self, kdht: &mut kad::Behaviour<MemoryStore>) -> HashMap<PeerId, u64> {
+        let now = unix_now();
+        let mut last_seen = HashMap::new();
+        for peer in self.peers {
+            if now.saturating_sub(peer.last_seen) > PEER_TTL.as_secs() {
+                continue;
+            }
+            for address in peer.addresses {
+                kdht.add_address(&peer.peer_id, address);
+            }
+            last_seen.insert(peer.peer_id, peer.last_seen);
+        }
+
+        for record in self.records {
+            let expires = match record.expires {
+                Some(expires) if expires <= now => continue,
+                Some(expires) => Some(Instant::now() + Duration::from_secs(expires - now)),
+                None => None,
+            };
+            let record = Record {
+                key: RecordKey::from(record.key),
+                value: record.value,
+                publisher: record.publisher,
+                expires,
+            };
+            if let Err(e) = kdht.store_mut().put(record) {
+                tracing::warn!("Failed to restore record: {e}");
+            }
+        }
+        tracing::info!("Restored {} peers from the peer store", last_seen.len());
+
+        last_seen
+    }
+
+    pub(crate) fn capture(kdht: &mut kad::Behaviour<MemoryStore>, last_seen: &HashMap<PeerId, u64>) -> Self {
+        let now = unix_now();
+        let mut peers = Vec::new();
+        for bucket in kdht.kbuckets() {
+            for entry in bucket.iter() {
+                let peer_id = *entry.node.key.preimage();
+                // skip peers never connected by this run or a previous one, they would never expire
+                let Some(seen) = last_seen.get(&peer_id).copied() else {
+                    continue;
+                };
+                if now.saturating_sub(seen) > PEER_TTL.as_secs() {
+                    continue;
+                }
+                peers.push(StoredPeer {
+                    peer_id,
+                    addresses: entry.node.value.iter().cloned().collect(),
+                    last_seen: seen,
+                });
+            }
+        }
+
+        let instant_now = Instant::now();
+        let records = kdht.store_mut().records()
+            .filter(|record| !record.is_expired(instant_now))
+            .map(|record| StoredRecord {
+                key: record.key.to_vec(),
+                value: record.value.clone(),
+                publisher: record.publisher,
+                expires: record.expires.map(|expires| now + expires.saturating_duration_since(instant_now).as_secs()),
+            })
+            .collect();
+
+        PeerStore { peers, records }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = serde_json::to_vec(self)?;
+        // write and rename, so a crash never leaves a truncated file behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, path)
+    }
+}
@@ -0,0 +1,36 @@
+//! Records published to the DHT are wrapped in a libp2p signed envelope, so readers can tell who
+//! published a value and nodes refuse to store records nobody signed.
+
+use libp2p::{core::SignedEnvelope, identity::Keypair, kad::{Record, RecordKey}, PeerId};
+
+const RECORD_DOMAIN: &str = "posemesh-dht-record";
+
+/// A value read from the DHT, with the peer whose signature it carries.
+#[derive(Debug, Clone)]
+pub struct DhtRecord {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub publisher: PeerId,
+}
+
+pub(crate) fn sign(keypair: &Keypair, key: &str, value: Vec<u8>) -> Result<Record, String> {
+    // The record key is used as payload type, so a signed value can't be replayed under another key.
+    let envelope = SignedEnvelope::new(keypair, RECORD_DOMAIN.to_string(), key.as_bytes().to_vec(), value)
+        .map_err(|e| e.to_string())?;
+    let mut record = Record::new(RecordKey::new(&key), envelope.into_protobuf_encoding());
+    record.publisher = Some(keypair.public().to_peer_id());
+    Ok(record)
+}
+
+/// Checks the signature of a record and returns its value and the peer that signed it.
+pub(crate) fn verify(record: &Record) -> Result<(Vec<u8>, PeerId), String> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(&record.value).map_err(|e| e.to_string())?;
+    let (value, public_key) = envelope
+        .payload_and_signing_key(RECORD_DOMAIN.to_string(), record.key.as_ref())
+        .map_err(|e| e.to_string())?;
+    let signer = public_key.to_peer_id();
+    if record.publisher.is_some_and(|publisher| publisher != signer) {
+        return Err(format!("record published by {:?} but signed by {signer}", record.publisher));
+    }
+    Ok((value.to_vec(), signer))
+}
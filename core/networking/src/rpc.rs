@@ -0,0 +1,428 @@
+//! Typed request/response calls on top of libp2p streams.
+//!
+//! A request is a single length prefixed protobuf message. The handler answers with frames of
+//! the form `[kind: u8][length: u32 BE][payload]`: any number of response messages, followed by
+//! either an end marker or an error (`[status: u8][utf-8 message]`).
+
+use crate::{client::Client, error::NetworkingError};
+use futures::{channel::mpsc, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future, SinkExt, StreamExt};
+use libp2p::{PeerId, Stream};
+use quick_protobuf::{deserialize_from_slice, serialize_into_vec, MessageRead, MessageWrite};
+use std::{error::Error, fmt, marker::PhantomData, sync::Arc, time::Duration};
+use web_time::Instant;
+
+#[cfg(not(target_family="wasm"))]
+use tokio::spawn;
+#[cfg(target_family="wasm")]
+use wasm_bindgen_futures::spawn_local as spawn;
+
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_ERROR: u8 = 1;
+const FRAME_END: u8 = 2;
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+// number of response messages queued before the handler has to wait for the stream
+const RESPONSE_BUFFER_SIZE: usize = 16;
+
+#[cfg(not(target_family="wasm"))]
+pub type ResponseStream<Resp> = futures::stream::BoxStream<'static, Result<Resp, NetworkingError>>;
+#[cfg(target_family="wasm")]
+pub type ResponseStream<Resp> = futures::stream::LocalBoxStream<'static, Result<Resp, NetworkingError>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcStatus {
+    InvalidRequest,
+    Unauthorized,
+    NotFound,
+    Unavailable,
+    Internal,
+}
+
+impl RpcStatus {
+    fn to_u8(self) -> u8 {
+        match self {
+            RpcStatus::InvalidRequest => 1,
+            RpcStatus::Unauthorized => 2,
+            RpcStatus::NotFound => 3,
+            RpcStatus::Unavailable => 4,
+            RpcStatus::Internal => 5,
+        }
+    }
+
+    fn from_u8(status: u8) -> Self {
+        match status {
+            1 => RpcStatus::InvalidRequest,
+            2 => RpcStatus::Unauthorized,
+            3 => RpcStatus::NotFound,
+            4 => RpcStatus::Unavailable,
+            _ => RpcStatus::Internal,
+        }
+    }
+}
+
+/// Error returned by a handler, delivered to the caller as [`NetworkingError::Rpc`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub status: RpcStatus,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(status: RpcStatus, message: impl Into<String>) -> Self {
+        RpcError { status, message: message.into() }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(1 + self.message.len());
+        payload.push(self.status.to_u8());
+        payload.extend_from_slice(self.message.as_bytes());
+        payload
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        match payload.split_first() {
+            Some((status, message)) => RpcError::new(RpcStatus::from_u8(*status), String::from_utf8_lossy(message)),
+            None => RpcError::new(RpcStatus::Internal, ""),
+        }
+    }
+}
+
+impl Error for RpcError {}
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.status, self.message)
+    }
+}
+
+impl From<NetworkingError> for RpcError {
+    fn from(e: NetworkingError) -> Self {
+        match e {
+            NetworkingError::Rpc(e) => e,
+            e => RpcError::new(RpcStatus::Internal, e.to_string()),
+        }
+    }
+}
+
+/// Sends response messages of a streaming handler back to the caller.
+pub struct ResponseSink<Resp> {
+    sender: mpsc::Sender<Vec<u8>>,
+    _response: PhantomData<fn(&Resp)>,
+}
+
+impl<Resp: MessageWrite> ResponseSink<Resp> {
+    /// Fails with [`NetworkingError::ChannelClosed`] once the caller has gone away.
+    pub async fn send(&mut self, message: &Resp) -> Result<(), NetworkingError> {
+        let payload = serialize_into_vec(message).map_err(|e| NetworkingError::InvalidMessage(e.to_string()))?;
+        self.sender.send(payload).await?;
+        Ok(())
+    }
+}
+
+fn encode_request<Req: MessageWrite>(request: &Req) -> Result<Vec<u8>, NetworkingError> {
+    let mut payload = serialize_into_vec(request).map_err(|e| NetworkingError::InvalidMessage(e.to_string()))?;
+    let mut message = Vec::with_capacity(4 + payload.len());
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.append(&mut payload);
+    Ok(message)
+}
+
+async fn read_request<Req: for<'a> MessageRead<'a>>(stream: &mut (impl AsyncRead + Unpin)) -> Result<Req, NetworkingError> {
+    let mut size_buffer = [0u8; 4];
+    stream.read_exact(&mut size_buffer).await?;
+    let size = u32::from_be_bytes(size_buffer) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(NetworkingError::InvalidMessage(format!("request of {size} bytes is too large")));
+    }
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload).await?;
+    deserialize_from_slice(&payload).map_err(|e| NetworkingError::InvalidMessage(e.to_string()))
+}
+
+async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), kind: u8, payload: &[u8]) -> Result<(), NetworkingError> {
+    let mut header = [0u8; 5];
+    header[0] = kind;
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    stream.write_all(&header).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// None marks the end of the response.
+async fn read_frame<Resp: for<'a> MessageRead<'a>>(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Resp>, NetworkingError> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await?;
+    let size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(NetworkingError::InvalidMessage(format!("response of {size} bytes is too large")));
+    }
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload).await?;
+
+    match header[0] {
+        FRAME_MESSAGE => deserialize_from_slice(&payload).map(Some).map_err(|e| NetworkingError::InvalidMessage(e.to_string())),
+        FRAME_ERROR => Err(NetworkingError::Rpc(RpcError::decode(&payload))),
+        FRAME_END => Ok(None),
+        kind => Err(NetworkingError::InvalidMessage(format!("unknown frame kind {kind}"))),
+    }
+}
+
+async fn read_frame_with_deadline<Resp>(mut stream: Stream, deadline: Duration) -> Result<(Stream, Option<Resp>), NetworkingError>
+where
+    Resp: for<'a> MessageRead<'a> + Send + 'static,
+{
+    utils::timeout(deadline, async move {
+        let frame = read_frame::<Resp>(&mut stream).await;
+        frame.map(|frame| (stream, frame))
+    }).await.map_err(|_| NetworkingError::Timeout)?
+}
+
+// Time left of a deadline that started at `started`; zero waits forever.
+fn remaining(deadline: Duration, started: Instant) -> Result<Duration, NetworkingError> {
+    if deadline.is_zero() {
+        return Ok(Duration::ZERO);
+    }
+    deadline.checked_sub(started.elapsed()).filter(|left| !left.is_zero()).ok_or(NetworkingError::Timeout)
+}
+
+// Timeout in milliseconds as taken by Client::send, where 0 waits forever.
+fn millis(timeout: Duration) -> u32 {
+    if timeout.is_zero() {
+        return 0;
+    }
+    u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX).max(1)
+}
+
+/// Calls a unary handler registered with [`serve`] on `peer_id`. The deadline bounds the whole
+/// call, from opening the stream to receiving the response; zero waits forever.
+pub async fn call<Req, Resp>(client: &mut Client, peer_id: String, protocol: String, request: &Req, deadline: Duration) -> Result<Resp, NetworkingError>
+where
+    Req: MessageWrite,
+    Resp: for<'a> MessageRead<'a> + Send + 'static,
+{
+    let started = Instant::now();
+    let request = encode_request(request)?;
+    let stream = client.send(request, peer_id, protocol, millis(deadline)).await?;
+
+    let (mut stream, response) = read_frame_with_deadline::<Resp>(stream, remaining(deadline, started)?).await?;
+    let _ = stream.close().await;
+    response.ok_or_else(|| NetworkingError::InvalidMessage("empty response".to_string()))
+}
+
+/// Calls a handler registered with [`serve_streaming`] on `peer_id`. The deadline bounds opening
+/// the stream and the wait for each response message; zero waits forever.
+pub async fn call_streaming<Req, Resp>(client: &mut Client, peer_id: String, protocol: String, request: &Req, deadline: Duration) -> Result<ResponseStream<Resp>, NetworkingError>
+where
+    Req: MessageWrite,
+    Resp: for<'a> MessageRead<'a> + Send + 'static,
+{
+    let request = encode_request(request)?;
+    let stream = client.send(request, peer_id, protocol, millis(deadline)).await?;
+
+    let responses = futures::stream::unfold(Some(stream), move |stream| async move {
+        match read_frame_with_deadline::<Resp>(stream?, deadline).await {
+            Ok((stream, Some(response))) => Some((Ok(response), Some(stream))),
+            Ok((_, None)) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    #[cfg(not(target_family="wasm"))]
+    return Ok(responses.boxed());
+    #[cfg(target_family="wasm")]
+    return Ok(responses.boxed_local());
+}
+
+/// Registers a unary handler for `protocol`. Every request is handled in its own task.
+pub async fn serve<Req, Resp, F, Fut>(client: &mut Client, protocol: String, handler: F) -> Result<(), NetworkingError>
+where
+    Req: for<'a> MessageRead<'a> + Send + 'static,
+    Resp: MessageWrite + Send + 'static,
+    F: Fn(PeerId, Req) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Resp, RpcError>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    serve_streaming(client, protocol, move |peer_id, request, mut sink: ResponseSink<Resp>| {
+        let handler = handler.clone();
+        async move {
+            let response = handler(peer_id, request).await?;
+            sink.send(&response).await?;
+            Ok(())
+        }
+    }).await
+}
+
+/// Registers a server-streaming handler for `protocol`. The response ends when the handler
+/// returns; an error is delivered to the caller after the messages already sent.
+pub async fn serve_streaming<Req, Resp, F, Fut>(client: &mut Client, protocol: String, handler: F) -> Result<(), NetworkingError>
+where
+    Req: for<'a> MessageRead<'a> + Send + 'static,
+    Resp: MessageWrite + Send + 'static,
+    F: Fn(PeerId, Req, ResponseSink<Resp>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), RpcError>> + Send + 'static,
+{
+    let mut incoming = client.set_stream_handler(protocol).await?;
+    let handler = Arc::new(handler);
+
+    spawn(async move {
+        while let Some((peer_id, stream)) = incoming.next().await {
+            spawn(handle_request(peer_id, stream, handler.clone()));
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_request<Req, Resp, F, Fut>(peer_id: PeerId, mut stream: Stream, handler: Arc<F>)
+where
+    Req: for<'a> MessageRead<'a>,
+    Resp: MessageWrite,
+    F: Fn(PeerId, Req, ResponseSink<Resp>) -> Fut,
+    Fut: Future<Output = Result<(), RpcError>>,
+{
+    let request = match read_request::<Req>(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::warn!("Invalid request from {peer_id}: {e}");
+            let _ = write_frame(&mut stream, FRAME_ERROR, &RpcError::new(RpcStatus::InvalidRequest, e.to_string()).encode()).await;
+            let _ = stream.close().await;
+            return;
+        }
+    };
+
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(RESPONSE_BUFFER_SIZE);
+    let handling = handler(peer_id, request, ResponseSink { sender, _response: PhantomData });
+    let forwarding = async {
+        while let Some(payload) = receiver.next().await {
+            if let Err(e) = write_frame(&mut stream, FRAME_MESSAGE, &payload).await {
+                // Unblocks the handler, its next send fails.
+                receiver.close();
+                return Err(e);
+            }
+        }
+        Ok::<(), NetworkingError>(())
+    };
+    let (result, forwarded) = futures::join!(handling, forwarding);
+
+    if let Err(e) = forwarded {
+        tracing::warn!("Failed to respond to {peer_id}: {e}");
+        return;
+    }
+    let written = match result {
+        Ok(()) => write_frame(&mut stream, FRAME_END, &[]).await,
+        Err(e) => write_frame(&mut stream, FRAME_ERROR, &e.encode()).await,
+    };
+    if let Err(e) = written {
+        tracing::warn!("Failed to respond to {peer_id}: {e}");
+    }
+    let _ = stream.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+    use quick_protobuf::{sizeofs::sizeof_len, BytesReader, Writer, WriterBackend};
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Text {
+        value: String,
+    }
+
+    impl<'a> MessageRead<'a> for Text {
+        fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> quick_protobuf::Result<Self> {
+            let mut msg = Self::default();
+            while !r.is_eof() {
+                match r.next_tag(bytes) {
+                    Ok(10) => msg.value = r.read_string(bytes)?.to_owned(),
+                    Ok(t) => { r.read_unknown(bytes, t)?; }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(msg)
+        }
+    }
+
+    impl MessageWrite for Text {
+        fn get_size(&self) -> usize {
+            1 + sizeof_len(self.value.len())
+        }
+
+        fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> quick_protobuf::Result<()> {
+            w.write_with_tag(10, |w| w.write_string(&self.value))
+        }
+    }
+
+    fn frame(kind: u8, payload: &[u8]) -> Cursor<Vec<u8>> {
+        let mut buffer = Cursor::new(Vec::new());
+        block_on(write_frame(&mut buffer, kind, payload)).unwrap();
+        buffer.set_position(0);
+        buffer
+    }
+
+    #[test]
+    fn rpc_error_round_trip() {
+        for status in [RpcStatus::InvalidRequest, RpcStatus::Unauthorized, RpcStatus::NotFound, RpcStatus::Unavailable, RpcStatus::Internal] {
+            let error = RpcError::new(status, "no such domain");
+            assert_eq!(RpcError::decode(&error.encode()), error);
+        }
+    }
+
+    #[test]
+    fn rpc_error_with_unknown_status_is_internal() {
+        assert_eq!(RpcError::decode(&[&[42][..], b"oops"].concat()), RpcError::new(RpcStatus::Internal, "oops"));
+        assert_eq!(RpcError::decode(&[]), RpcError::new(RpcStatus::Internal, ""));
+    }
+
+    #[test]
+    fn deadline_is_shared_by_the_steps_of_a_call() {
+        let started = Instant::now() - Duration::from_secs(2);
+        assert!(remaining(Duration::from_secs(10), started).unwrap() <= Duration::from_secs(8));
+        assert!(matches!(remaining(Duration::from_secs(1), started), Err(NetworkingError::Timeout)));
+        assert_eq!(remaining(Duration::ZERO, started).unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn timeout_millis_saturate() {
+        assert_eq!(millis(Duration::ZERO), 0);
+        assert_eq!(millis(Duration::from_micros(10)), 1);
+        assert_eq!(millis(Duration::from_secs(u64::MAX)), u32::MAX);
+    }
+
+    #[test]
+    fn read_message_frame() {
+        let message = Text { value: "hello".to_string() };
+        let mut stream = frame(FRAME_MESSAGE, &serialize_into_vec(&message).unwrap());
+        assert_eq!(block_on(read_frame::<Text>(&mut stream)).unwrap(), Some(message));
+    }
+
+    #[test]
+    fn read_error_frame() {
+        let error = RpcError::new(RpcStatus::NotFound, "no such domain");
+        let mut stream = frame(FRAME_ERROR, &error.encode());
+        match block_on(read_frame::<Text>(&mut stream)) {
+            Err(NetworkingError::Rpc(e)) => assert_eq!(e, error),
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn read_end_frame() {
+        let mut stream = frame(FRAME_END, &[]);
+        assert_eq!(block_on(read_frame::<Text>(&mut stream)).unwrap(), None);
+    }
+
+    #[test]
+    fn read_frame_of_unknown_kind_fails() {
+        let mut stream = frame(7, b"payload");
+        assert!(matches!(block_on(read_frame::<Text>(&mut stream)), Err(NetworkingError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let size = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        let mut stream = Cursor::new([&[FRAME_MESSAGE][..], &size].concat());
+        assert!(matches!(block_on(read_frame::<Text>(&mut stream)), Err(NetworkingError::InvalidMessage(_))));
+
+        let mut stream = Cursor::new(size.to_vec());
+        assert!(matches!(block_on(read_request::<Text>(&mut stream)), Err(NetworkingError::InvalidMessage(_))));
+    }
+}
@@ -0,0 +1,62 @@
+#![cfg(not(target_family="wasm"))]
+
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use networking::{error::NetworkingError, libp2p::{Networking, NetworkingConfig, TransportMode}};
+
+const PROTOCOL: &str = "/echo/v1";
+
+fn node_config(name: &str, bootstrap_nodes: Vec<String>, pre_shared_key: [u8; 32]) -> NetworkingConfig {
+    NetworkingConfig {
+        port: 0,
+        bootstrap_nodes,
+        enable_kdht: true,
+        enable_mdns: false,
+        // a new key for every node
+        private_key_path: None,
+        name: format!("private-swarm/{}", name),
+        pre_shared_key: Some(pre_shared_key),
+        transport: TransportMode::Memory,
+        ..Default::default()
+    }
+}
+
+async fn echo(node: &mut Networking, peer_id: String) -> Result<(), NetworkingError> {
+    let mut s = node.client.send(b"ping".to_vec(), peer_id, PROTOCOL.to_string(), 5000).await?;
+    s.close().await?;
+    let mut buf = Vec::new();
+    s.read_to_end(&mut buf).await?;
+    assert_eq!(buf, b"ping");
+    Ok(())
+}
+
+// Nodes only connect when they share the same pre-shared key: a member of the private network
+// reaches the bootstrap node, a stranger with another key can't.
+#[tokio::test]
+async fn only_members_connect_to_private_swarm() {
+    let mut bootstrap = Networking::start(&node_config("bootstrap", vec![], [1u8; 32])).await.unwrap();
+    let mut echo_handler = bootstrap.client.set_stream_handler(PROTOCOL.to_string()).await.unwrap();
+    tokio::spawn(async move {
+        while let Some((_, mut stream)) = echo_handler.next().await {
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.close().await.unwrap();
+        }
+    });
+    let listen_addr = bootstrap.client.listen_addresses().await.unwrap().remove(0);
+    let bootstrap_addr = format!("{}/p2p/{}", listen_addr, bootstrap.id);
+
+    let mut member = Networking::start(&node_config("member", vec![bootstrap_addr.clone()], [1u8; 32])).await.unwrap();
+    let mut stranger = Networking::start(&node_config("stranger", vec![], [2u8; 32])).await.unwrap();
+    // the stranger knows the address, so the dial itself must fail rather than the lookup
+    stranger.client.add_peer_address(bootstrap.id.clone(), bootstrap_addr).await.unwrap();
+
+    echo(&mut member, bootstrap.id.clone()).await.unwrap();
+    match echo(&mut stranger, bootstrap.id.clone()).await {
+        Err(NetworkingError::DialFailure(_)) => {}
+        res => panic!("the stranger should fail to dial: {res:?}"),
+    }
+    let connected_peers = bootstrap.client.connected_peers().await.unwrap();
+    assert!(connected_peers.iter().any(|peer| peer.peer_id.to_string() == member.id));
+    assert!(connected_peers.iter().all(|peer| peer.peer_id.to_string() != stranger.id));
+}
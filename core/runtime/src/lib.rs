@@ -1,5 +1,6 @@
 use once_cell::sync::Lazy;
-use tokio::runtime::Runtime;
+use std::{future::Future, sync::mpsc};
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
 
 static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
     Runtime::new().expect("Failed to create Tokio runtime")
@@ -9,3 +10,22 @@ static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
 pub fn get_runtime() -> &'static Runtime {
     &*RUNTIME
 }
+
+/// Runs `future` on the global runtime and blocks the calling thread until it completes. Unlike
+/// `Runtime::block_on` it may be called from async code: the future never needs the caller's
+/// runtime, and a multi-threaded caller hands its other tasks to another worker meanwhile.
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    get_runtime().spawn(async move {
+        let _ = sender.send(future.await);
+    });
+    let wait = move || receiver.recv().expect("the future panicked");
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(wait),
+        _ => wait(),
+    }
+}
@@ -19,6 +19,8 @@ extern "C" {
 
 psm_posemesh_t* PSM_API psm_posemesh_create();
 psm_posemesh_t* PSM_API psm_posemesh_create_with_config(const psm_config_t* config);
+// The callback gets status 1 when the message was sent and 0 when it failed, see
+// psm_posemesh_get_last_send_error for the reason.
 uint8_t PSM_API psm_posemesh_send_message(
     const psm_posemesh_t* posemesh,
     const void* message,
@@ -35,6 +37,9 @@ uint8_t PSM_API psm_posemesh_send_string(
     const char* protocol,
     void* user_data,
     void (*callback)(uint8_t status, void* user_data));
+// Reason of the failure, one of the PSM_POSEMESH_NETWORKING_ERROR_* codes of
+// Posemesh/Networking/API.h. Only valid inside a send callback called with status 0.
+uint8_t PSM_API psm_posemesh_get_last_send_error();
 void PSM_API psm_posemesh_destroy(psm_posemesh_t* posemesh);
 
 const char* PSM_API psm_posemesh_get_version();
@@ -90,6 +90,11 @@ uint8_t psm_posemesh_send_string(
         callback);
 }
 
+uint8_t psm_posemesh_get_last_send_error()
+{
+    return psm_posemesh_networking_get_last_error();
+}
+
 void psm_posemesh_destroy(psm_posemesh_t* posemesh)
 {
     delete posemesh;